@@ -0,0 +1,22 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+fn pong(c: &mut Criterion) {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../../projects/06/pong/Pong.asm"
+    );
+    let source = std::fs::read_to_string(path).unwrap();
+    let mut group = c.benchmark_group("pong");
+
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("lex", |b| {
+        b.iter(|| assembler::lexer::Lexer::new(&source).count())
+    });
+    group.bench_function("assemble", |b| {
+        b.iter(|| assembler::assemble(&source).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, pong);
+criterion_main!(benches);
@@ -0,0 +1,293 @@
+// builds the control-flow graph of an assembled program. a basic block starts at the first
+// instruction, at every label, at every address a jump is known to land on, and after every
+// jump, and runs up to the next one.
+//
+// a jump's target is known when A was loaded by an A-instruction earlier in the same block and
+// nothing has written to A since, as in `@LOOP, D;JGT`. a label is an entry point that can be
+// reached with any value in A, so nothing is assumed about A across one, just like the
+// optimizer. anything else, like the `A=M, 0;JMP` the VM translator emits for `return`, jumps
+// somewhere that depends on what's in RAM, and gets an indirect edge.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::tokens::{AddrToken, Instruction, JumpToken};
+use crate::Program;
+
+/// A run of instructions that's only entered at the top and only left at the bottom.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// The ROM address of the first instruction.
+    pub start: usize,
+    /// The ROM address after the last instruction.
+    pub end: usize,
+    /// The labels on the first instruction, in alphabetical order.
+    pub labels: Vec<String>,
+}
+
+/// How control gets from one block to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Running off the end of a block into the next one.
+    Fallthrough,
+    /// An unconditional jump to a known address.
+    Jump,
+    /// A conditional jump to a known address, taken when the condition holds.
+    Branch,
+    /// A jump to an address that's only known at run time.
+    Indirect,
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fallthrough => "fallthrough",
+            Self::Jump => "jump",
+            Self::Branch => "branch",
+            Self::Indirect => "indirect",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edge {
+    /// The index of the block the edge leaves.
+    pub from: usize,
+    /// The index of the block the edge enters, or `None` for an indirect jump.
+    pub to: Option<usize>,
+    pub kind: EdgeKind,
+    /// The jump that takes the edge, or `None` for a fallthrough.
+    pub jump: Option<JumpToken>,
+}
+
+/// The control-flow graph of a program.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Graph {
+    /// The blocks in ROM order.
+    pub blocks: Vec<Block>,
+    /// The edges in the order of the blocks they leave, the taken jump before the fallthrough.
+    pub edges: Vec<Edge>,
+}
+
+/// The formats `cfg` can write a graph in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz's DOT language.
+    #[default]
+    Dot,
+    Json,
+}
+
+impl GraphFormat {
+    /// The name `--format` knows the format by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dot => "dot",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Dot, Self::Json]
+            .into_iter()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| format!("unknown graph format `{s}`, expected `dot` or `json`"))
+    }
+}
+
+impl Graph {
+    /// Splits `program` into basic blocks and connects them.
+    pub fn build(program: &Program) -> Self {
+        let instructions = &program.instructions;
+        let labels = program.labels.keys().map(|label| program.symbols[label]);
+        let mut leaders = entries(instructions, labels);
+
+        for (i, instruction) in instructions.iter().enumerate() {
+            if matches!(instruction, Instruction::Comp(_, _, jump) if *jump != JumpToken::Empty) {
+                leaders.insert(i + 1);
+            }
+        }
+
+        let starts: Vec<usize> = leaders
+            .into_iter()
+            .filter(|&start| start < instructions.len())
+            .collect();
+        let leaders: BTreeSet<usize> = starts.iter().copied().collect();
+
+        let mut blocks: Vec<Block> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| Block {
+                start,
+                end: starts.get(i + 1).copied().unwrap_or(instructions.len()),
+                labels: vec![],
+            })
+            .collect();
+
+        for label in program.labels.keys() {
+            if let Ok(i) = starts.binary_search(&program.symbols[label]) {
+                blocks[i].labels.push(label.clone());
+            }
+        }
+
+        for block in &mut blocks {
+            block.labels.sort();
+        }
+
+        let targets: HashMap<usize, Option<usize>> =
+            jumps(instructions, &leaders).into_iter().collect();
+        let mut edges = vec![];
+
+        for (from, block) in blocks.iter().enumerate() {
+            let last = block.end - 1;
+            let falls_through = block.end < instructions.len();
+
+            let jump = match &instructions[last] {
+                Instruction::Comp(_, _, jump) if *jump != JumpToken::Empty => jump,
+                _ => {
+                    if falls_through {
+                        edges.push(Edge::fallthrough(from));
+                    }
+
+                    continue;
+                }
+            };
+
+            // a jump past the last instruction leaves the program, so there's nothing to draw
+            let edge = match targets[&last] {
+                Some(target) => starts.binary_search(&target).ok().map(|to| Edge {
+                    from,
+                    to: Some(to),
+                    kind: match jump {
+                        JumpToken::JMP => EdgeKind::Jump,
+                        _ => EdgeKind::Branch,
+                    },
+                    jump: Some(jump.clone()),
+                }),
+                None => Some(Edge {
+                    from,
+                    to: None,
+                    kind: EdgeKind::Indirect,
+                    jump: Some(jump.clone()),
+                }),
+            };
+
+            edges.extend(edge);
+
+            if *jump != JumpToken::JMP && falls_through {
+                edges.push(Edge::fallthrough(from));
+            }
+        }
+
+        Self { blocks, edges }
+    }
+}
+
+impl Edge {
+    fn fallthrough(from: usize) -> Self {
+        Self {
+            from,
+            to: Some(from + 1),
+            kind: EdgeKind::Fallthrough,
+            jump: None,
+        }
+    }
+}
+
+/// The addresses control can get to other than by running on from the instruction before: the
+/// first one, the ones with `labels` on them, and every one a jump is known to land on.
+pub(crate) fn entries(
+    instructions: &[Instruction],
+    labels: impl IntoIterator<Item = usize>,
+) -> BTreeSet<usize> {
+    let mut entries: BTreeSet<usize> = labels.into_iter().chain([0]).collect();
+
+    // the targets found with only the labels as entry points are a superset of the final ones,
+    // since splitting blocks only forgets what's in A
+    let targets: Vec<usize> = jumps(instructions, &entries)
+        .into_iter()
+        .filter_map(|(_, target)| target)
+        .collect();
+
+    entries.extend(targets);
+    entries
+}
+
+// the address of every jump, and where it lands when A was loaded in the same block
+fn jumps(instructions: &[Instruction], leaders: &BTreeSet<usize>) -> Vec<(usize, Option<usize>)> {
+    let mut jumps = vec![];
+    let mut a = None;
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        if leaders.contains(&i) {
+            a = None;
+        }
+
+        match instruction {
+            Instruction::Addr(AddrToken::Static(addr)) => a = Some(*addr),
+            Instruction::Addr(_) => a = None,
+            Instruction::Comp(dest, _, jump) => {
+                if *jump != JumpToken::Empty {
+                    jumps.push((i, a));
+                    // the next instruction starts a block
+                    a = None;
+                }
+
+                if dest.code() & 0b100 != 0 {
+                    a = None;
+                }
+            }
+        }
+    }
+
+    jumps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    #[test]
+    fn splits_blocks_at_labels_and_jumps() {
+        let source =
+            "@R0\nD=M\n(LOOP)\n@END\nD;JLE\nD=D-1\n@LOOP\n0;JMP\n(END)\n@R14\nA=M\n0;JMP\n";
+        let graph = Graph::build(&assemble(source).unwrap());
+
+        let blocks: Vec<_> = graph
+            .blocks
+            .iter()
+            .map(|block| (block.start, block.end, block.labels.join(",")))
+            .collect();
+
+        assert_eq!(
+            blocks,
+            [
+                (0, 2, String::new()),
+                (2, 4, "LOOP".to_string()),
+                (4, 7, String::new()),
+                (7, 10, "END".to_string()),
+            ]
+        );
+
+        let edges: Vec<_> = graph
+            .edges
+            .iter()
+            .map(|edge| (edge.from, edge.to, edge.kind))
+            .collect();
+
+        assert_eq!(
+            edges,
+            [
+                (0, Some(1), EdgeKind::Fallthrough),
+                (1, Some(3), EdgeKind::Branch),
+                (1, Some(2), EdgeKind::Fallthrough),
+                (2, Some(1), EdgeKind::Jump),
+                (3, None, EdgeKind::Indirect),
+            ]
+        );
+    }
+}
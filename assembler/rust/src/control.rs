@@ -0,0 +1,367 @@
+// structured control flow. the preprocessor lowers `.if`, `.while` and `.loop` blocks into plain
+// instructions that jump between labels of their own, which are named like the labels in macro
+// expansions so they can't clash with the program's:
+//
+//     .if D>0        @if.1$else         .while D!=0    (while.2$top)
+//       ...          D;JLE                ...          @while.2$end
+//     .else          ...                .endwhile      D;JEQ
+//       ...          @if.1$end                         ...
+//     .endif         0;JMP                             @while.2$top
+//                    (if.1$else)                       0;JMP
+//                    ...                               (while.2$end)
+//                    (if.1$end)
+//
+// a condition compares a computation with zero, like `D>0`, `D-1!=0` or `!D==0`, and the block
+// is skipped by jumping on the opposite condition. A already holds the label to jump to by the
+// time the condition is computed, so conditions can only read D. `.loop N` runs its body N times, where N is
+// anything an A-instruction can load, keeping count in a variable of its own (`loop.3$count`) so
+// the body is free to use every register.
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::tokens::{CompToken, JumpToken};
+
+// the lines a directive is lowered into, or why it can't be
+type Lowered = Result<Vec<String>, Box<Diagnostic>>;
+
+const CONDITION: &str = "conditions compare a computation with 0 using >, >=, <, <=, == or !=";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    If,
+    While,
+    Loop,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::If => "if",
+            Self::While => "while",
+            Self::Loop => "loop",
+        }
+    }
+
+    fn end(&self) -> &'static str {
+        match self {
+            Self::If => ".endif",
+            Self::While => ".endwhile",
+            Self::Loop => ".endloop",
+        }
+    }
+}
+
+struct Block {
+    kind: Kind,
+    id: usize,
+    has_else: bool,
+    span: Span,
+    /// The file or macro expansion the block was opened in, which it has to be closed in too.
+    context: usize,
+}
+
+/// The control flow blocks that are open while preprocessing.
+#[derive(Default)]
+pub(crate) struct Blocks {
+    open: Vec<Block>,
+    count: usize,
+}
+
+impl Blocks {
+    /// Lowers a control flow directive into the lines of assembly it stands for, which are
+    /// empty when it's invalid. Returns `None` when `name` isn't a control flow directive.
+    pub fn lower(
+        &mut self,
+        name: &str,
+        args: &str,
+        span: Span,
+        context: usize,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Option<Vec<String>> {
+        let lines = match name {
+            ".if" => self.open(Kind::If, args, span, context),
+            ".while" => self.open(Kind::While, args, span, context),
+            ".loop" => self.open(Kind::Loop, args, span, context),
+            ".else" => self.otherwise(span, context),
+            ".endif" => self.close(Kind::If, span, context),
+            ".endwhile" => self.close(Kind::While, span, context),
+            ".endloop" => self.close(Kind::Loop, span, context),
+            _ => return None,
+        };
+
+        match lines {
+            Ok(lines) => Some(lines),
+            Err(diagnostic) => {
+                diagnostics.push(*diagnostic);
+                Some(vec![])
+            }
+        }
+    }
+
+    /// How many blocks are open.
+    pub fn depth(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Reports the blocks opened in `context` that were never closed, and forgets them.
+    pub fn unclosed(&mut self, context: usize) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+
+        while let Some(block) = self.open.pop_if(|block| block.context == context) {
+            let name = block.kind.name();
+
+            diagnostics.push(
+                Diagnostic::error(format!("the `.{name}` is never closed"), block.span)
+                    .with_hint(format!("end it with `{}`", block.kind.end())),
+            );
+        }
+
+        diagnostics
+    }
+
+    fn open(&mut self, kind: Kind, args: &str, span: Span, context: usize) -> Lowered {
+        let id = self.count + 1;
+        let name = kind.name();
+        let label = |suffix: &str| format!("{name}.{id}${suffix}");
+
+        let lines = match kind {
+            Kind::If => {
+                let (comp, jump) = condition(name, args, span)?;
+
+                vec![
+                    format!("@{}", label("else")),
+                    format!("{comp};{}", jump.negate()),
+                ]
+            }
+
+            Kind::While => {
+                let (comp, jump) = condition(name, args, span)?;
+
+                vec![
+                    format!("({})", label("top")),
+                    format!("@{}", label("end")),
+                    format!("{comp};{}", jump.negate()),
+                ]
+            }
+
+            Kind::Loop => {
+                if args.is_empty() {
+                    return Err(Box::new(
+                        Diagnostic::error("expected a count after `.loop`".to_string(), span)
+                            .with_hint("e.g. `.loop 8`, `.loop ROWS` or `.loop ROWS*2`"),
+                    ));
+                }
+
+                vec![
+                    format!("@{args}"),
+                    "D=A".to_string(),
+                    format!("@{}", label("count")),
+                    "M=D".to_string(),
+                    format!("({})", label("top")),
+                    format!("@{}", label("count")),
+                    "D=M".to_string(),
+                    format!("@{}", label("end")),
+                    "D;JLE".to_string(),
+                ]
+            }
+        };
+
+        self.count = id;
+        self.open.push(Block {
+            kind,
+            id,
+            has_else: false,
+            span,
+            context,
+        });
+
+        Ok(lines)
+    }
+
+    fn otherwise(&mut self, span: Span, context: usize) -> Lowered {
+        let block = match self.open.last_mut() {
+            Some(block) if block.context == context && block.kind == Kind::If => block,
+            _ => {
+                return Err(Box::new(Diagnostic::error(
+                    "`.else` without a matching `.if`".to_string(),
+                    span,
+                )))
+            }
+        };
+
+        if block.has_else {
+            return Err(Box::new(
+                Diagnostic::error("this `.if` already has an `.else`".to_string(), span)
+                    .with_note(block.span, "the `.if` starts here"),
+            ));
+        }
+
+        block.has_else = true;
+
+        Ok(vec![
+            format!("@if.{}$end", block.id),
+            "0;JMP".to_string(),
+            format!("(if.{}$else)", block.id),
+        ])
+    }
+
+    fn close(&mut self, kind: Kind, span: Span, context: usize) -> Lowered {
+        let end = kind.end();
+        let block = match self.open.last() {
+            Some(block) if block.context == context => block,
+            _ => {
+                return Err(Box::new(Diagnostic::error(
+                    format!("`{end}` without a matching `.{}`", kind.name()),
+                    span,
+                )))
+            }
+        };
+
+        if block.kind != kind {
+            return Err(Box::new(
+                Diagnostic::error(
+                    format!(
+                        "`{end}` doesn't close the `.{}` before it",
+                        block.kind.name()
+                    ),
+                    span,
+                )
+                .with_note(
+                    block.span,
+                    format!("close this with `{}` first", block.kind.end()),
+                ),
+            ));
+        }
+
+        let Block { id, has_else, .. } = self.open.pop().unwrap();
+
+        Ok(match kind {
+            Kind::If if has_else => vec![format!("(if.{id}$end)")],
+            Kind::If => vec![format!("(if.{id}$else)")],
+            Kind::While => vec![
+                format!("@while.{id}$top"),
+                "0;JMP".to_string(),
+                format!("(while.{id}$end)"),
+            ],
+            Kind::Loop => vec![
+                format!("@loop.{id}$count"),
+                "M=M-1".to_string(),
+                format!("@loop.{id}$top"),
+                "0;JMP".to_string(),
+                format!("(loop.{id}$end)"),
+            ],
+        })
+    }
+}
+
+/// Splits a runtime condition like `D>0` into the computation and the jump that's taken when
+/// it holds. Returns `None` if `text` isn't one.
+pub(crate) fn parse_condition(text: &str) -> Option<(&str, JumpToken)> {
+    const OPERATORS: [(&str, JumpToken); 6] = [
+        ("!=", JumpToken::JNE),
+        ("==", JumpToken::JEQ),
+        (">=", JumpToken::JGE),
+        ("<=", JumpToken::JLE),
+        (">", JumpToken::JGT),
+        ("<", JumpToken::JLT),
+    ];
+
+    let (i, operator, jump) = text.char_indices().find_map(|(i, _)| {
+        OPERATORS
+            .iter()
+            .find(|(operator, _)| text[i..].starts_with(operator))
+            .map(|(operator, jump)| (i, *operator, jump.clone()))
+    })?;
+
+    let comp = &text[..i];
+
+    if text[i + operator.len()..] != *"0" || CompToken::parse(comp).is_none() {
+        return None;
+    }
+
+    Some((comp, jump))
+}
+
+fn condition<'a>(
+    name: &str,
+    args: &'a str,
+    span: Span,
+) -> Result<(&'a str, JumpToken), Box<Diagnostic>> {
+    let (comp, jump) = parse_condition(args).ok_or_else(|| {
+        let message = if args.is_empty() {
+            format!("expected a condition after `.{name}`")
+        } else {
+            format!("`{args}` is not a condition")
+        };
+
+        Box::new(
+            Diagnostic::error(message, span).with_hint(format!("{CONDITION}, e.g. `.{name} D>0`")),
+        )
+    })?;
+
+    if CompToken::parse(comp).is_some_and(|comp| reads_a(&comp)) {
+        return Err(Box::new(
+            Diagnostic::error(format!("the condition `{args}` reads A or M"), span).with_hint(
+                format!(
+                    "A holds the address to jump to when the condition is tested, so load the \
+                     value into D first, e.g. `D=M` and then `.{name} D!=0`"
+                ),
+            ),
+        ));
+    }
+
+    Ok((comp, jump))
+}
+
+// whether a computation reads A, or M through it
+fn reads_a(comp: &CompToken) -> bool {
+    match comp {
+        CompToken::ShiftLeftD | CompToken::ShiftRightD => false,
+        comp if comp.is_shift() => true,
+        // the y input is A or M unless zy zeroes it
+        comp => comp.code() & 0b0001000 == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble;
+
+    fn lower(source: &str) -> Vec<String> {
+        let program = assemble(source).unwrap();
+        program.instructions.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn lowers_blocks_to_jumps() {
+        assert_eq!(
+            lower(".if D>0\nD=1\n.else\nD=-1\n.endif\n"),
+            ["@5", "D;JLE", "D=1", "@6", "0;JMP", "D=-1"]
+        );
+        assert_eq!(
+            lower(".while D-1!=0\nD=D-1\n.endwhile\n"),
+            ["@5", "D-1;JEQ", "D=D-1", "@0", "0;JMP"]
+        );
+        assert_eq!(
+            lower(".loop 3\nM=0\n.endloop\n")[..4],
+            ["@3", "D=A", "@16", "M=D"]
+        );
+    }
+
+    #[test]
+    fn reports_mismatched_blocks() {
+        let diagnostics =
+            assemble(".if D>0\n.endwhile\n.else\n.while X\n.while M!=0\n.if D&A==0\n").unwrap_err();
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            [
+                "the `.if` is never closed",
+                "`.endwhile` doesn't close the `.if` before it",
+                "`X` is not a condition",
+                "the condition `M!=0` reads A or M",
+                "the condition `D&A==0` reads A or M",
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::fmt::Write;
+
+use crate::source::Sources;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A range of columns on a single line of one of the [`Sources`]. Lines and columns start at 1
+/// and `end` is exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, start: usize, end: usize) -> Self {
+        Self {
+            file: 0,
+            line,
+            start,
+            end,
+        }
+    }
+
+    pub fn in_file(self, file: usize) -> Self {
+        Self { file, ..self }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The lint a warning came from, which `// hack-allow(id)` suppresses.
+    pub id: Option<&'static str>,
+    /// The name of the file `span` is in, filled in once parsing is finished.
+    pub file: Option<String>,
+    pub span: Span,
+    pub message: String,
+    pub hint: Option<String>,
+    /// Secondary locations, e.g. the macro invocation a line was expanded from.
+    pub notes: Vec<(Span, String)>,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, span: Span) -> Self {
+        Self {
+            severity: Severity::Error,
+            id: None,
+            file: None,
+            span,
+            message,
+            hint: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(id: &'static str, message: String, span: Span) -> Self {
+        Self {
+            severity: Severity::Warning,
+            id: Some(id),
+            ..Self::error(message, span)
+        }
+    }
+
+    pub fn with_note(mut self, span: Span, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Renders the diagnostic the way rustc does, with a caret under the offending columns
+    /// of the quoted source line.
+    pub fn render(&self, sources: &Sources) -> String {
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        let mut out = format!("{}: {}\n", self.heading(), self.message);
+
+        snippet(&mut out, sources, self.span);
+
+        if let Some(hint) = &self.hint {
+            write!(out, "\n{gutter} |\n{gutter} = help: {hint}").unwrap();
+        }
+
+        for (span, note) in &self.notes {
+            write!(out, "\nnote: {note}\n").unwrap();
+            snippet(&mut out, sources, *span);
+        }
+
+        out
+    }
+
+    // the severity, along with the lint for warnings, e.g. `warning[unused-label]`
+    fn heading(&self) -> String {
+        match self.id {
+            Some(id) => format!("{}[{id}]", self.severity),
+            None => self.severity.to_string(),
+        }
+    }
+}
+
+fn snippet(out: &mut String, sources: &Sources, span: Span) {
+    let Span {
+        file,
+        line,
+        start,
+        end,
+    } = span;
+    let name = sources.name(file);
+    let text = sources
+        .get(file)
+        .and_then(|file| file.text.lines().nth(line - 1))
+        .unwrap_or("");
+    let gutter = " ".repeat(line.to_string().len());
+
+    // keep tabs in the padding so the carets line up with the quoted line
+    let padding: String = text
+        .chars()
+        .take(start - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    writeln!(out, "{gutter}--> {name}:{line}:{start}").unwrap();
+    writeln!(out, "{gutter} |").unwrap();
+    writeln!(out, "{line} | {}", text.trim_end()).unwrap();
+    write!(out, "{gutter} | {padding}{}", "^".repeat(end - start)).unwrap();
+}
+
+/// Every diagnostic produced while assembling a program, in source order, along with the
+/// sources they refer to.
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+    pub diagnostics: Vec<Diagnostic>,
+    pub sources: Sources,
+}
+
+impl Diagnostics {
+    /// Renders every diagnostic the way rustc does, separated by blank lines.
+    pub fn render(&self) -> String {
+        self.diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render(&self.sources) + "\n")
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn len(&self) -> usize {
+        self.diagnostics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.diagnostics.iter()
+    }
+}
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for diagnostic in &self.diagnostics {
+            let Span { line, start, .. } = diagnostic.span;
+            let file = diagnostic.file.as_deref().unwrap_or("<input>");
+            writeln!(
+                f,
+                "{file}:{line}:{start}: {}: {}",
+                diagnostic.heading(),
+                diagnostic.message
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_caret_under_span() {
+        let mut sources = Sources::new();
+        sources.add("Add.asm", "@2\nD=X+1\n");
+        let diagnostic = Diagnostic::error("invalid computation".to_string(), Span::new(2, 3, 6));
+
+        assert_eq!(
+            diagnostic.render(&sources),
+            "error: invalid computation\n --> Add.asm:2:3\n  |\n2 | D=X+1\n  |   ^^^"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::tokens::{AddrToken, CompToken, DestToken, Instruction, Isa, JumpToken};
+
+/// The result of disassembling a ROM image.
+#[derive(Debug)]
+pub struct Disassembly {
+    /// Hack assembly that assembles back into the same ROM image, apart from unknown words.
+    /// Computations without a name are written as `%comp(...)` escapes, with a comment flagging
+    /// them.
+    pub source: String,
+    /// ROM addresses of the words that don't decode into an instruction. Each one is replaced
+    /// with a `0` placeholder (which has no effect) so later addresses don't move.
+    pub unknown: Vec<usize>,
+    /// ROM addresses of the C-instructions whose comp bits don't map to any documented
+    /// computation. They still assemble back into the same words.
+    pub undocumented: Vec<usize>,
+}
+
+/// Reads a .hack file in either the course's text format (one line of 16 `0`s and `1`s per word)
+/// or as raw big-endian 16-bit words.
+pub fn read_hack(bytes: &[u8]) -> Result<Vec<u16>, String> {
+    let is_text = bytes
+        .iter()
+        .all(|byte| matches!(byte, b'0' | b'1' | b' ' | b'\t' | b'\r' | b'\n'));
+
+    if is_text {
+        let text = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+
+        text.lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(n, line)| {
+                if line.len() != 16 {
+                    return Err(format!(
+                        "line {n}: expected 16 bits but found {}",
+                        line.len()
+                    ));
+                }
+
+                u16::from_str_radix(line, 2).map_err(|e| format!("line {n}: {e}"))
+            })
+            .collect()
+    } else if !bytes.len().is_multiple_of(2) {
+        Err(format!(
+            "binary ROM images must hold whole 16-bit words but this one is {} bytes long",
+            bytes.len()
+        ))
+    } else {
+        Ok(bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+}
+
+/// Turns a ROM image back into Hack assembly.
+///
+/// Every address loaded by an `@n` that feeds a jump gets a synthesized `(L_nnnn)` label, and
+/// well-known RAM addresses are annotated with their predefined symbol names. Words are decoded
+/// as instructions from `isa`.
+pub fn disassemble(words: &[u16], isa: Isa) -> Disassembly {
+    let instructions: Vec<Option<Instruction>> = words
+        .iter()
+        .map(|&word| Instruction::decode_for(word, isa))
+        .collect();
+    let targets = jump_targets(&instructions);
+    let mut source = String::new();
+    let mut unknown = vec![];
+    let mut undocumented = vec![];
+
+    for (addr, instruction) in instructions.iter().enumerate() {
+        if targets.contains(&addr) {
+            writeln!(source, "{}", label_definition(addr)).unwrap();
+        }
+
+        let next = instructions.get(addr + 1).and_then(Option::as_ref);
+
+        match instruction {
+            Some(Instruction::Addr(AddrToken::Static(value))) if feeds_jump(next) => {
+                if targets.contains(value) {
+                    writeln!(source, "    @{}", label_name(*value)).unwrap();
+                } else {
+                    writeln!(source, "    @{value} // jump target outside the ROM image").unwrap();
+                }
+            }
+
+            Some(instruction @ Instruction::Addr(AddrToken::Static(value))) => {
+                match ram_name(*value, next) {
+                    Some(name) => writeln!(source, "    {instruction} // {name}").unwrap(),
+                    None => writeln!(source, "    {instruction}").unwrap(),
+                }
+            }
+
+            Some(instruction @ Instruction::Comp(_, CompToken::Raw(_), _)) => {
+                undocumented.push(addr);
+                writeln!(source, "    {instruction} // undocumented computation").unwrap();
+            }
+
+            Some(instruction) => writeln!(source, "    {instruction}").unwrap(),
+
+            None => {
+                unknown.push(addr);
+                writeln!(
+                    source,
+                    "    0 // unknown instruction {:016b} ({})",
+                    words[addr],
+                    unknown_reason(words[addr], isa)
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    // a jump to the address just past the last instruction is common for "halt" loops that
+    // have been trimmed, so it still gets a label
+    if targets.contains(&words.len()) {
+        writeln!(source, "{}", label_definition(words.len())).unwrap();
+    }
+
+    Disassembly {
+        source,
+        unknown,
+        undocumented,
+    }
+}
+
+fn jump_targets(instructions: &[Option<Instruction>]) -> BTreeSet<usize> {
+    instructions
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [Some(Instruction::Addr(AddrToken::Static(value))), next]
+                if feeds_jump(next.as_ref()) =>
+            {
+                Some(*value)
+            }
+            _ => None,
+        })
+        .filter(|&value| value <= instructions.len())
+        .collect()
+}
+
+fn feeds_jump(next: Option<&Instruction>) -> bool {
+    matches!(next, Some(Instruction::Comp(_, _, jump)) if *jump != JumpToken::Empty)
+}
+
+fn label_name(addr: usize) -> String {
+    format!("L_{addr:04}")
+}
+
+fn label_definition(addr: usize) -> String {
+    format!("({})", label_name(addr))
+}
+
+fn uses_memory(instruction: Option<&Instruction>) -> bool {
+    match instruction {
+        Some(Instruction::Comp(dest, comp, _)) => {
+            comp.code() & 0b1000000 != 0
+                || matches!(
+                    dest,
+                    DestToken::M | DestToken::MD | DestToken::AM | DestToken::ADM
+                )
+        }
+        _ => false,
+    }
+}
+
+// SCREEN and KBD are almost always used as base addresses, so they're named wherever they appear.
+// the low registers are small enough to be ordinary constants, so they're only named when the
+// following instruction actually touches memory.
+fn ram_name(value: usize, next: Option<&Instruction>) -> Option<String> {
+    match value {
+        16384 => Some("SCREEN".to_string()),
+        24576 => Some("KBD".to_string()),
+        _ if !uses_memory(next) => None,
+        0 => Some("SP".to_string()),
+        1 => Some("LCL".to_string()),
+        2 => Some("ARG".to_string()),
+        3 => Some("THIS".to_string()),
+        4 => Some("THAT".to_string()),
+        5..=15 => Some(format!("R{value}")),
+        _ => None,
+    }
+}
+
+// every word that starts with 111 decodes, since comp bits without a name are escaped
+fn unknown_reason(word: u16, isa: Isa) -> String {
+    let comp = (word as usize) >> 6 & 0b1111111;
+
+    match word >> 13 {
+        0b101 if isa == Isa::Extended => format!("comp bits {comp:07b} aren't a shift"),
+        0b101 => "a shift from the extended instruction set".to_string(),
+        bits => format!("bits 14 and 13 are {:02b} rather than 11", bits & 0b11),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_the_assembler() {
+        let source = "@SCREEN\nD=A\n(LOOP)\n@SP\nM=D\n@LOOP\nD;JGT\n@END\n0;JMP\n(END)\n";
+        let words = crate::assemble(source).unwrap().words;
+        let disassembly = disassemble(&words, Isa::Standard);
+
+        assert_eq!(
+            disassembly.source,
+            "    @16384 // SCREEN\n    D=A\n(L_0002)\n    @0 // SP\n    M=D\n    @L_0002\n    D;JGT\n    @L_0008\n    0;JMP\n(L_0008)\n"
+        );
+        assert_eq!(crate::assemble(&disassembly.source).unwrap().words, words);
+    }
+
+    #[test]
+    fn escapes_undocumented_comp_bits_and_flags_unknown_words() {
+        let words = [0b1110000001010000, 0b1100101010000111, 0b1010110000001000];
+        let disassembly = disassemble(&words, Isa::Standard);
+
+        assert_eq!(disassembly.unknown, vec![1, 2]);
+        assert_eq!(disassembly.undocumented, vec![0]);
+        assert!(disassembly
+            .source
+            .starts_with("    D=%comp(0b0000001) // undocumented computation\n"));
+        assert!(disassembly
+            .source
+            .contains("bits 14 and 13 are 10 rather than 11"));
+        assert!(disassembly
+            .source
+            .contains("a shift from the extended instruction set"));
+        assert!(disassemble(&words, Isa::Extended)
+            .source
+            .ends_with("    M=D<<\n"));
+    }
+
+    #[test]
+    fn reads_text_and_binary_images() {
+        assert_eq!(
+            read_hack(b"0000000000000010\r\n1110110000010000\n"),
+            Ok(vec![2, 0xec10])
+        );
+        assert_eq!(read_hack(&[0x00, 0x02, 0xec, 0x10]), Ok(vec![2, 0xec10]));
+        assert!(read_hack(b"0101\n").is_err());
+    }
+}
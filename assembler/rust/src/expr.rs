@@ -0,0 +1,411 @@
+// constant expressions, as used in `@` operands and `.equ` definitions. they're parsed while
+// lexing but can refer to labels, so they're only evaluated once the first pass is done.
+//
+//     compare = expr (("==" | "!=" | "<" | "<=" | ">" | ">=") expr)?
+//     expr    = term (("+" | "-") term)*
+//     term    = unary (("*" | "/" | "%") unary)*
+//     unary   = "-" unary | primary
+//     primary = number | symbol | "(" expr ")"
+//
+// numbers are decimal, or hex and binary with 0x and 0b prefixes. comparisons evaluate to 1 or 0
+// and can only be used in conditions, like `.if LEVEL>1`, so `@` operands start at `expr`.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Eq | Self::Ne | Self::Lt | Self::Le | Self::Gt | Self::Ge => 0,
+            Self::Add | Self::Sub => 1,
+            Self::Mul | Self::Div | Self::Rem => 2,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// A problem with an expression. `offset` is the byte offset into the expression's text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExprError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, ExprError> {
+        let mut parser = ExprParser { text, position: 0 };
+        let expr = parser.expr()?;
+
+        parser.skip_whitespace();
+
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(parser.error(format!("unexpected `{c}` in expression"))),
+        }
+    }
+
+    /// Parses a condition: an expression, or two compared with `==`, `!=`, `<`, `<=`, `>` or
+    /// `>=`.
+    pub fn parse_comparison(text: &str) -> Result<Expr, ExprError> {
+        let mut parser = ExprParser { text, position: 0 };
+        let expr = parser.comparison()?;
+
+        parser.skip_whitespace();
+
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(parser.error(format!("unexpected `{c}` in expression"))),
+        }
+    }
+
+    /// Evaluates the expression, looking up symbols with `lookup`.
+    pub fn eval(&self, lookup: &mut dyn FnMut(&str) -> Result<i64, String>) -> Result<i64, String> {
+        let overflow = || "expression overflows".to_string();
+
+        match self {
+            Self::Number(n) => Ok(*n),
+            Self::Symbol(name) => lookup(name),
+            Self::Neg(expr) => expr.eval(lookup)?.checked_neg().ok_or_else(overflow),
+            Self::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(lookup)?;
+                let rhs = rhs.eval(lookup)?;
+
+                match op {
+                    BinaryOp::Add => lhs.checked_add(rhs).ok_or_else(overflow),
+                    BinaryOp::Sub => lhs.checked_sub(rhs).ok_or_else(overflow),
+                    BinaryOp::Mul => lhs.checked_mul(rhs).ok_or_else(overflow),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        Err("division by zero".to_string())
+                    }
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or_else(overflow),
+                    BinaryOp::Rem => lhs.checked_rem(rhs).ok_or_else(overflow),
+                    BinaryOp::Eq => Ok((lhs == rhs) as i64),
+                    BinaryOp::Ne => Ok((lhs != rhs) as i64),
+                    BinaryOp::Lt => Ok((lhs < rhs) as i64),
+                    BinaryOp::Le => Ok((lhs <= rhs) as i64),
+                    BinaryOp::Gt => Ok((lhs > rhs) as i64),
+                    BinaryOp::Ge => Ok((lhs >= rhs) as i64),
+                }
+            }
+        }
+    }
+
+    /// Every symbol the expression refers to, in order.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Self::Number(_) => vec![],
+            Self::Symbol(name) => vec![name.as_str()],
+            Self::Neg(expr) => expr.symbols(),
+            Self::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Every symbol the expression refers to, so they can be renamed.
+    pub fn symbols_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Self::Number(_) => vec![],
+            Self::Symbol(name) => vec![name],
+            Self::Neg(expr) => expr.symbols_mut(),
+            Self::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols_mut();
+                symbols.extend(rhs.symbols_mut());
+                symbols
+            }
+        }
+    }
+
+    fn fmt_with_precedence(&self, f: &mut fmt::Formatter<'_>, outer: u8) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Symbol(name) => write!(f, "{name}"),
+            Self::Neg(expr) => {
+                write!(f, "-")?;
+                expr.fmt_with_precedence(f, 3)
+            }
+            Self::Binary(op, lhs, rhs) => {
+                let precedence = op.precedence();
+
+                if precedence < outer {
+                    write!(f, "(")?;
+                }
+
+                lhs.fmt_with_precedence(f, precedence)?;
+                write!(f, "{}", op.symbol())?;
+                // operators are left associative, so a right operand at the same level needs
+                // parentheses to keep its meaning
+                rhs.fmt_with_precedence(f, precedence + 1)?;
+
+                if precedence < outer {
+                    write!(f, ")")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with_precedence(f, 0)
+    }
+}
+
+struct ExprParser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn accept(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, message: String) -> ExprError {
+        ExprError {
+            offset: self.position,
+            message,
+        }
+    }
+
+    fn accept_str(&mut self, s: &str) -> bool {
+        self.skip_whitespace();
+
+        if self.text[self.position..].starts_with(s) {
+            self.position += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExprError> {
+        let lhs = self.expr()?;
+
+        // the two-character operators go first so `<=` isn't read as `<`
+        let op = match () {
+            _ if self.accept_str("==") => BinaryOp::Eq,
+            _ if self.accept_str("!=") => BinaryOp::Ne,
+            _ if self.accept_str("<=") => BinaryOp::Le,
+            _ if self.accept_str(">=") => BinaryOp::Ge,
+            _ if self.accept('<') => BinaryOp::Lt,
+            _ if self.accept('>') => BinaryOp::Gt,
+            _ => return Ok(lhs),
+        };
+
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(self.expr()?)))
+    }
+
+    fn expr(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.term()?;
+
+        loop {
+            let op = match () {
+                _ if self.accept('+') => BinaryOp::Add,
+                _ if self.accept('-') => BinaryOp::Sub,
+                _ => return Ok(lhs),
+            };
+
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+
+        loop {
+            let op = match () {
+                _ if self.accept('*') => BinaryOp::Mul,
+                _ if self.accept('/') => BinaryOp::Div,
+                _ if self.accept('%') => BinaryOp::Rem,
+                _ => return Ok(lhs),
+            };
+
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.accept('-') {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let expr = self.expr()?;
+
+                if self.accept(')') {
+                    Ok(expr)
+                } else {
+                    Err(self.error("expected `)`".to_string()))
+                }
+            }
+            Some('0'..='9') => self.number(),
+            Some('A'..='Z' | 'a'..='z' | '_' | '$' | '.') => Ok(Expr::Symbol(self.symbol())),
+            Some(c) => Err(self.error(format!("expected a number or symbol but found `{c}`"))),
+            None => Err(self.error("expected a number or symbol".to_string())),
+        }
+    }
+
+    fn number(&mut self) -> Result<Expr, ExprError> {
+        let start = self.position;
+        let rest = &self.text[start..];
+        let (radix, prefix) = match rest.get(..2) {
+            Some("0x" | "0X") => (16, 2),
+            Some("0b" | "0B") => (2, 2),
+            _ => (10, 0),
+        };
+        let digits = rest[prefix..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len() - prefix);
+        let literal = &rest[..prefix + digits];
+
+        self.position += literal.len();
+
+        i64::from_str_radix(&literal[prefix..], radix)
+            .map(Expr::Number)
+            .map_err(|_| ExprError {
+                offset: start,
+                message: format!("`{literal}` is not a valid number"),
+            })
+    }
+
+    fn symbol(&mut self) -> String {
+        let rest = &self.text[self.position..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$')))
+            .unwrap_or(rest.len());
+
+        self.position += len;
+        rest[..len].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<i64, String> {
+        let expr = Expr::parse(text).map_err(|e| e.message)?;
+
+        expr.eval(&mut |name| match name {
+            "SCREEN" => Ok(16384),
+            "LOOP" => Ok(10),
+            _ => Err(format!("`{name}` is not defined")),
+        })
+    }
+
+    #[test]
+    fn evaluates_with_precedence_and_radix_prefixes() {
+        assert_eq!(eval("SCREEN+32*5"), Ok(16544));
+        assert_eq!(eval("LOOP-1"), Ok(9));
+        assert_eq!(eval("0x4000"), Ok(16384));
+        assert_eq!(eval("0b1010"), Ok(10));
+        assert_eq!(eval("(2 + 3) * -(4 - 1) % 7"), Ok(-1));
+        assert_eq!(eval("8/(4-4)"), Err("division by zero".to_string()));
+        assert_eq!(
+            eval("(-9223372036854775807-1)/-1"),
+            Err("expression overflows".to_string())
+        );
+        assert_eq!(
+            eval("(-9223372036854775807-1)%-1"),
+            Err("expression overflows".to_string())
+        );
+        assert_eq!(eval("X+1"), Err("`X` is not defined".to_string()));
+    }
+
+    #[test]
+    fn compares_only_in_conditions() {
+        let expr = Expr::parse_comparison("LOOP - 1 >= 9").unwrap();
+
+        assert_eq!(expr.eval(&mut |_| Ok(10)), Ok(1));
+        assert_eq!(expr.to_string(), "LOOP-1>=9");
+        assert!(Expr::parse("LOOP>9").is_err());
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        assert_eq!(
+            Expr::parse("SCREEN+(1"),
+            Err(ExprError {
+                offset: 9,
+                message: "expected `)`".to_string()
+            })
+        );
+        assert_eq!(
+            Expr::parse("0x").unwrap_err().message,
+            "`0x` is not a valid number"
+        );
+    }
+
+    #[test]
+    fn displays_with_minimal_parentheses() {
+        let expr = Expr::parse("(SCREEN + (32 * 5)) - (1 - 2)").unwrap();
+        assert_eq!(expr.to_string(), "SCREEN+32*5-(1-2)");
+    }
+}
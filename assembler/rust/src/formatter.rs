@@ -0,0 +1,269 @@
+// the formatter works a line at a time on the original source, like the preprocessor, since the
+// lexer throws comments away and macros, includes and blocks have to survive as written. it:
+//
+//   - puts labels flush-left and indents everything else, one more level inside each `.macro`,
+//     `.if`, `.ifdef`, `.ifndef`, `.while` and `.loop` block
+//   - spells C-instructions the canonical way, e.g. `MD=M+1` for `DM=1+M`
+//   - lines up the trailing comments in each run of code between blank or comment lines
+//   - collapses runs of blank lines into one, and drops them at the start and end of the file
+//
+// anything it doesn't understand, like a line that doesn't assemble, keeps its spelling and only
+// has its whitespace tidied, so formatting never changes what a program assembles to. A-
+// instructions keep the operand as written, since `@0x4000` and `@16384` say different things.
+
+use crate::lexer::comment_start;
+use crate::tokens::{CompToken, DestToken};
+
+const INDENT: &str = "    ";
+
+/// Formats Hack assembly in the canonical style. Formatting the result again changes nothing.
+pub fn format(source: &str) -> String {
+    let mut lines: Vec<Line> = vec![];
+    let mut depth = 0;
+
+    for text in source.lines() {
+        let (code, comment) = match comment_start(text) {
+            Some(i) => (text[..i].trim(), Some(text[i..].trim_end())),
+            None => (text.trim(), None),
+        };
+
+        let line = match (code, comment) {
+            ("", None) => Line::Blank,
+            ("", Some(comment)) => {
+                // comments at the start of a line stay there, and the rest follow the code
+                let indent = match text.starts_with("//") {
+                    true => 0,
+                    false => depth + 1,
+                };
+
+                Line::Comment(indent, comment)
+            }
+            (code, comment) => {
+                let (indent, code) = code_line(code, &mut depth);
+                Line::Code(indent, code, comment)
+            }
+        };
+
+        let repeated_blank =
+            matches!(line, Line::Blank) && matches!(lines.last(), None | Some(Line::Blank));
+
+        if !repeated_blank {
+            lines.push(line);
+        }
+    }
+
+    while let Some(Line::Blank) = lines.last() {
+        lines.pop();
+    }
+
+    let mut formatted = String::new();
+    // the lines between the groups of code, in order
+    let mut separators = lines.iter().filter(|line| !matches!(line, Line::Code(..)));
+
+    for group in lines.split(|line| !matches!(line, Line::Code(..))) {
+        // trailing comments line up two spaces after the longest line of code that has one
+        let column = group
+            .iter()
+            .filter_map(|line| match line {
+                Line::Code(indent, code, Some(_)) => Some(width(*indent, code)),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        for line in group {
+            if let Line::Code(indent, code, comment) = line {
+                formatted.push_str(&INDENT.repeat(*indent));
+                formatted.push_str(code);
+
+                if let Some(comment) = comment {
+                    let padding = column - width(*indent, code) + 2;
+                    formatted.push_str(&" ".repeat(padding));
+                    formatted.push_str(comment);
+                }
+
+                formatted.push('\n');
+            }
+        }
+
+        match separators.next() {
+            Some(Line::Comment(indent, comment)) => {
+                formatted.push_str(&INDENT.repeat(*indent));
+                formatted.push_str(comment);
+                formatted.push('\n');
+            }
+            Some(Line::Blank) => formatted.push('\n'),
+            _ => (),
+        }
+    }
+
+    formatted
+}
+
+enum Line<'a> {
+    Blank,
+    /// A comment alone on its line, and how many levels it's indented.
+    Comment(usize, &'a str),
+    /// A line of code, how many levels it's indented, and its trailing comment.
+    Code(usize, String, Option<&'a str>),
+}
+
+// the indentation and canonical spelling of a line of code, keeping track of the blocks it opens
+// and closes
+fn code_line(code: &str, depth: &mut usize) -> (usize, String) {
+    if code.starts_with('(') || is_numeric_label(code) {
+        return (0, code.to_string());
+    }
+
+    if code.starts_with('@') {
+        return (*depth + 1, code.to_string());
+    }
+
+    let Some(directive) = code.strip_prefix('.') else {
+        let text = instruction(code).unwrap_or_else(|| tidy(code));
+        return (*depth + 1, text);
+    };
+
+    let name = directive.split_whitespace().next().unwrap_or_default();
+
+    match name {
+        "macro" | "if" | "ifdef" | "ifndef" | "while" | "loop" => {
+            *depth += 1;
+            (*depth, tidy(code))
+        }
+        "else" => ((*depth).max(1), tidy(code)),
+        "endm" | "endif" | "endwhile" | "endloop" => {
+            *depth = depth.saturating_sub(1);
+            (*depth + 1, tidy(code))
+        }
+        _ => (*depth + 1, tidy(code)),
+    }
+}
+
+fn is_numeric_label(code: &str) -> bool {
+    code.strip_suffix(':')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+// a C-instruction in its canonical spelling, or `None` if `code` isn't one
+fn instruction(code: &str) -> Option<String> {
+    const JUMPS: [&str; 7] = ["JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+    let (rest, jump) = match code.rsplit_once(';') {
+        Some((rest, jump)) if JUMPS.contains(&jump) => (rest, Some(jump)),
+        Some(_) => return None,
+        None => (code, None),
+    };
+
+    // `%ALU(...)` has `=`s of its own, so the dest is only what comes before the first one
+    let (dest, comp) = match rest.split_once('=') {
+        Some((dest, comp)) => match DestToken::parse(dest) {
+            Some(dest) => (dest, comp),
+            None if dest.starts_with('%') => (DestToken::Empty, rest),
+            None => return None,
+        },
+        None => (DestToken::Empty, rest),
+    };
+
+    // computations without a name keep the escape they were written with
+    let comp = match CompToken::parse(comp)? {
+        CompToken::Raw(_) => tidy(comp),
+        comp => comp.to_string(),
+    };
+
+    let mut text = match dest {
+        DestToken::Empty => comp,
+        dest => format!("{dest}={comp}"),
+    };
+
+    if let Some(jump) = jump {
+        text.push(';');
+        text.push_str(jump);
+    }
+
+    Some(text)
+}
+
+// collapses each run of whitespace outside quotes into a single space
+fn tidy(code: &str) -> String {
+    let mut tidied = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in code.chars() {
+        if c.is_whitespace() && !quoted {
+            if !tidied.ends_with(' ') {
+                tidied.push(' ');
+            }
+
+            continue;
+        }
+
+        if quoted && escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        }
+
+        tidied.push(c);
+    }
+
+    tidied
+}
+
+fn width(indent: usize, code: &str) -> usize {
+    indent * INDENT.len() + code.chars().count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, assemble_files, assemble_sources, Sources};
+
+    #[test]
+    fn formats_in_the_canonical_style() {
+        let source = "\n\n// adds one\n  (START)\n@x   // the counter\nDM=1+M // bump\n\n\n\n\
+                      .if   D>0\n   M=%ALU(zx=0, nx=0,  zy=0,ny=0,f=0,no=1)\n  // positive\n\
+                      .else\n0;JMP\n.endif\n1:\n\n";
+
+        assert_eq!(
+            format(source),
+            "// adds one\n(START)\n    @x      // the counter\n    MD=M+1  // bump\n\n\
+             \x20   .if D>0\n        M=%ALU(zx=0, nx=0, zy=0,ny=0,f=0,no=1)\n        // positive\n\
+             \x20   .else\n        0;JMP\n    .endif\n1:\n"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent_and_keeps_the_program() {
+        for name in ["max/Max", "pong/Pong", "rect/Rect"] {
+            let path = format!(
+                "{}/../../projects/06/{name}.asm",
+                env!("CARGO_MANIFEST_DIR")
+            );
+            let source = std::fs::read_to_string(&path).unwrap();
+            let formatted = format(&source);
+
+            assert_eq!(format(&formatted), formatted);
+
+            let before = assemble_files(&[path]).unwrap().unwrap();
+            let after = assemble_sources(Sources::from(formatted.as_str())).unwrap();
+            assert_eq!(before.words, after.words);
+        }
+
+        // `//` in a string doesn't start a comment
+        let source = ".data URL\n.string   \"http://x\"   // where\n@URL\n";
+        let formatted = format(source);
+
+        assert_eq!(
+            formatted,
+            "    .data URL\n    .string \"http://x\"  // where\n    @URL\n"
+        );
+        assert_eq!(
+            assemble(source).unwrap().words,
+            assemble(&formatted).unwrap().words
+        );
+    }
+}
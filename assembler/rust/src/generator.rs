@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::cfg::{EdgeKind, Graph, GraphFormat};
+use crate::{Program, SymbolKind};
+
+/// The machine-code formats a program can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The course's text format: one line of 16 `0`s and `1`s per word.
+    Hack,
+    /// Raw big-endian 16-bit words.
+    Binary,
+    /// Intel HEX, byte addressed, with each word stored big-endian.
+    IntelHex,
+    /// One binary word per line, for Verilog's `$readmemb`.
+    ReadMemB,
+    /// One hex word per line, for Verilog's `$readmemh`.
+    ReadMemH,
+    /// An Altera (Intel) memory initialization file.
+    Mif,
+    /// A Xilinx coefficient file.
+    Coe,
+}
+
+impl Format {
+    pub const ALL: [Self; 7] = [
+        Self::Hack,
+        Self::Binary,
+        Self::IntelHex,
+        Self::ReadMemB,
+        Self::ReadMemH,
+        Self::Mif,
+        Self::Coe,
+    ];
+
+    /// The name `--format` knows the format by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hack => "hack",
+            Self::Binary => "bin",
+            Self::IntelHex => "ihex",
+            Self::ReadMemB => "memb",
+            Self::ReadMemH => "memh",
+            Self::Mif => "mif",
+            Self::Coe => "coe",
+        }
+    }
+
+    /// The extension of files written in the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Hack => "hack",
+            Self::Binary => "bin",
+            Self::IntelHex => "hex",
+            Self::ReadMemB | Self::ReadMemH => "mem",
+            Self::Mif => "mif",
+            Self::Coe => "coe",
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(Format::name).collect();
+                format!("unknown format `{s}`, expected one of {}", names.join(", "))
+            })
+    }
+}
+
+pub struct Generator<'a> {
+    dest: Box<dyn Write + 'a>,
+    program: &'a Program,
+}
+impl<'a> Generator<'a> {
+    pub fn new(dest: Box<dyn Write + 'a>, program: &'a Program) -> Generator<'a> {
+        Self { dest, program }
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.write(Format::Hack)
+    }
+
+    /// Writes the program's machine words in `format`.
+    pub fn write(&mut self, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+        let words = &self.program.words;
+
+        match format {
+            Format::Hack | Format::ReadMemB => {
+                for word in words {
+                    writeln!(self.dest, "{word:016b}")?;
+                }
+            }
+
+            Format::Binary => {
+                for word in words {
+                    self.dest.write_all(&word.to_be_bytes())?;
+                }
+            }
+
+            Format::IntelHex => {
+                // 8 words to a data record, then the end-of-file record
+                for (i, chunk) in words.chunks(8).enumerate() {
+                    let addr = (i * 16) as u16;
+                    let mut record = vec![(chunk.len() * 2) as u8];
+
+                    record.extend(addr.to_be_bytes());
+                    record.push(0x00);
+                    record.extend(chunk.iter().flat_map(|word| word.to_be_bytes()));
+
+                    write_hex_record(&mut self.dest, &record)?;
+                }
+
+                write_hex_record(&mut self.dest, &[0x00, 0x00, 0x00, 0x01])?;
+            }
+
+            Format::ReadMemH => {
+                for word in words {
+                    writeln!(self.dest, "{word:04x}")?;
+                }
+            }
+
+            Format::Mif => {
+                writeln!(self.dest, "WIDTH=16;")?;
+                writeln!(self.dest, "DEPTH={};", words.len())?;
+                writeln!(self.dest, "ADDRESS_RADIX=UNS;")?;
+                writeln!(self.dest, "DATA_RADIX=BIN;")?;
+                writeln!(self.dest, "CONTENT BEGIN")?;
+
+                for (addr, word) in words.iter().enumerate() {
+                    writeln!(self.dest, "\t{addr} : {word:016b};")?;
+                }
+
+                writeln!(self.dest, "END;")?;
+            }
+
+            Format::Coe => {
+                let vector: Vec<_> = words.iter().map(|word| format!("{word:016b}")).collect();
+
+                writeln!(self.dest, "memory_initialization_radix=2;")?;
+                writeln!(self.dest, "memory_initialization_vector=")?;
+                writeln!(self.dest, "{};", vector.join(",\n"))?;
+            }
+        }
+
+        Ok(self.dest.flush()?)
+    }
+
+    /// Writes a .lst listing: every line of every source file next to the ROM address and the
+    /// encoded word (in binary and hex) of the instruction on that line. Label definitions show
+    /// the address they resolve to.
+    pub fn write_listing(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut rows: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        let mut labels: HashMap<(usize, usize), usize> = HashMap::new();
+        let sources = &self.program.sources;
+
+        for (addr, span) in self.program.spans.iter().enumerate() {
+            rows.entry((span.file, span.line)).or_default().push(addr);
+        }
+
+        for (label, span) in &self.program.labels {
+            labels.insert((span.file, span.line), self.program.symbols[label]);
+        }
+
+        writeln!(
+            self.dest,
+            "{:>5}  {:16}  {:4}  {:>5}  Source",
+            "ROM", "Word", "Hex", "Line"
+        )?;
+
+        for (file, source) in sources.iter() {
+            if sources.iter().count() > 1 {
+                writeln!(self.dest, "\n// {}", source.name)?;
+            }
+
+            for (n, text) in source.text.lines().enumerate() {
+                let line = n + 1;
+                let text = text.trim_end();
+
+                match (rows.get(&(file, line)), labels.get(&(file, line))) {
+                    (Some(addrs), _) => {
+                        for addr in addrs {
+                            let word = self.program.words[*addr];
+                            writeln!(
+                                self.dest,
+                                "{addr:5}  {word:016b}  {word:04X}  {line:5}  {text}"
+                            )?;
+                        }
+                    }
+                    (None, Some(addr)) => {
+                        writeln!(self.dest, "{addr:5}  {:22}  {line:5}  {text}", "")?
+                    }
+                    (None, None) if text.is_empty() => writeln!(self.dest, "{:29}  {line:5}", "")?,
+                    (None, None) => writeln!(self.dest, "{:29}  {line:5}  {text}", "")?,
+                }
+            }
+        }
+
+        Ok(self.dest.flush()?)
+    }
+
+    /// Writes a .sym file: the symbol table split into predefined symbols, labels (ROM addresses)
+    /// variables (RAM addresses) and constants, each sorted by value.
+    pub fn write_symbols(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let sections = [
+            (SymbolKind::Predefined, "predefined symbols"),
+            (SymbolKind::Label, "labels (ROM)"),
+            (SymbolKind::Variable, "variables (RAM)"),
+            (SymbolKind::Table, "data tables (RAM)"),
+            (SymbolKind::Constant, "constants"),
+        ];
+
+        for (i, (kind, title)) in sections.into_iter().enumerate() {
+            let mut symbols: Vec<_> = self
+                .program
+                .symbols
+                .iter()
+                .filter(|(name, _)| self.program.symbol_kind(name) == Some(kind))
+                .map(|(name, &value)| (value, name.as_str()))
+                .collect();
+
+            symbols.sort();
+
+            if i > 0 {
+                writeln!(self.dest)?;
+            }
+
+            writeln!(self.dest, "// {title}")?;
+
+            for (value, name) in symbols {
+                writeln!(self.dest, "{name:24} {value:5}")?;
+            }
+        }
+
+        Ok(self.dest.flush()?)
+    }
+
+    /// Writes a JSON source map: for every ROM address, the file, line and columns of the
+    /// instruction it came from, and the label it falls under. Instructions expanded from a macro
+    /// map to the line that invoked it.
+    ///
+    /// ```json
+    /// {
+    ///   "version": 1,
+    ///   "files": ["Prog.asm"],
+    ///   "mappings": [
+    ///     {"address": 0, "file": 0, "line": 3, "column": 1, "end_column": 3, "label": "LOOP"}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Numeric labels are skipped, so an address falls under the closest named label before it,
+    /// or `null` when there isn't one.
+    pub fn write_source_map(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let sources = &self.program.sources;
+        let mut labels: Vec<_> = self
+            .program
+            .labels
+            .iter()
+            .filter(|(label, _)| !label.contains(':'))
+            .map(|(label, span)| (self.program.symbols[label], *span, label.as_str()))
+            .collect();
+
+        // of several labels on one address, the last one defined is the closest
+        labels.sort();
+
+        let files: Vec<_> = sources
+            .iter()
+            .map(|(id, _)| json_string(sources.name(id)))
+            .collect();
+
+        writeln!(self.dest, "{{")?;
+        writeln!(self.dest, "  \"version\": 1,")?;
+        writeln!(self.dest, "  \"files\": [{}],", files.join(", "))?;
+        writeln!(self.dest, "  \"mappings\": [")?;
+
+        for (addr, span) in self.program.spans.iter().enumerate() {
+            let label = match labels.partition_point(|(at, _, _)| *at <= addr) {
+                0 => "null".to_string(),
+                i => json_string(labels[i - 1].2),
+            };
+
+            writeln!(
+                self.dest,
+                "    {{\"address\": {addr}, \"file\": {}, \"line\": {}, \"column\": {}, \
+                 \"end_column\": {}, \"label\": {label}}}{}",
+                span.file,
+                span.line,
+                span.start,
+                span.end,
+                if addr + 1 < self.program.spans.len() {
+                    ","
+                } else {
+                    ""
+                }
+            )?;
+        }
+
+        writeln!(self.dest, "  ]")?;
+        writeln!(self.dest, "}}")?;
+
+        Ok(self.dest.flush()?)
+    }
+
+    /// Writes the program's control-flow graph in `format`. In DOT, every block is a box showing
+    /// its labels and ROM addresses, jumps are labelled with their condition, and indirect jumps
+    /// go to a node of their own. In JSON:
+    ///
+    /// ```json
+    /// {
+    ///   "version": 1,
+    ///   "blocks": [
+    ///     {"id": 0, "start": 0, "end": 4, "labels": ["LOOP"]}
+    ///   ],
+    ///   "edges": [
+    ///     {"from": 0, "to": 0, "kind": "branch", "jump": "JGT"},
+    ///     {"from": 0, "to": null, "kind": "indirect", "jump": "JMP"}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// `end` is exclusive, and `kind` is `fallthrough` (with a `null` jump), `jump`, `branch` or
+    /// `indirect` (with a `null` target).
+    pub fn write_graph(&mut self, format: GraphFormat) -> Result<(), Box<dyn std::error::Error>> {
+        let graph = Graph::build(self.program);
+
+        match format {
+            GraphFormat::Dot => self.write_dot(&graph),
+            GraphFormat::Json => self.write_graph_json(&graph),
+        }
+    }
+
+    fn write_dot(&mut self, graph: &Graph) -> Result<(), Box<dyn std::error::Error>> {
+        writeln!(self.dest, "digraph cfg {{")?;
+        writeln!(self.dest, "  node [shape=box, fontname=\"monospace\"];")?;
+
+        for (id, block) in graph.blocks.iter().enumerate() {
+            let mut label: Vec<String> = block.labels.clone();
+
+            label.push(match block.end - block.start {
+                1 => format!("ROM[{}]", block.start),
+                _ => format!("ROM[{}]-ROM[{}]", block.start, block.end - 1),
+            });
+
+            writeln!(
+                self.dest,
+                "  b{id} [label={}];",
+                dot_string(&label.join("\\n"))
+            )?;
+        }
+
+        if graph.edges.iter().any(|edge| edge.to.is_none()) {
+            writeln!(self.dest, "  indirect [shape=diamond, label=\"indirect\"];")?;
+        }
+
+        for edge in &graph.edges {
+            let to = match edge.to {
+                Some(to) => format!("b{to}"),
+                None => "indirect".to_string(),
+            };
+
+            let attributes = match (&edge.jump, edge.kind) {
+                (None, _) => String::new(),
+                (Some(jump), EdgeKind::Indirect) => format!(" [label=\"{jump}\", style=dashed]"),
+                (Some(jump), _) => format!(" [label=\"{jump}\"]"),
+            };
+
+            writeln!(self.dest, "  b{} -> {to}{attributes};", edge.from)?;
+        }
+
+        writeln!(self.dest, "}}")?;
+
+        Ok(self.dest.flush()?)
+    }
+
+    fn write_graph_json(&mut self, graph: &Graph) -> Result<(), Box<dyn std::error::Error>> {
+        writeln!(self.dest, "{{")?;
+        writeln!(self.dest, "  \"version\": 1,")?;
+        writeln!(self.dest, "  \"blocks\": [")?;
+
+        for (id, block) in graph.blocks.iter().enumerate() {
+            let labels: Vec<_> = block
+                .labels
+                .iter()
+                .map(|label| json_string(label))
+                .collect();
+
+            writeln!(
+                self.dest,
+                "    {{\"id\": {id}, \"start\": {}, \"end\": {}, \"labels\": [{}]}}{}",
+                block.start,
+                block.end,
+                labels.join(", "),
+                if id + 1 < graph.blocks.len() { "," } else { "" }
+            )?;
+        }
+
+        writeln!(self.dest, "  ],")?;
+        writeln!(self.dest, "  \"edges\": [")?;
+
+        for (i, edge) in graph.edges.iter().enumerate() {
+            let to = edge.to.map_or("null".to_string(), |to| to.to_string());
+            let jump = edge
+                .jump
+                .as_ref()
+                .map_or("null".to_string(), |jump| json_string(&jump.to_string()));
+
+            writeln!(
+                self.dest,
+                "    {{\"from\": {}, \"to\": {to}, \"kind\": \"{}\", \"jump\": {jump}}}{}",
+                edge.from,
+                edge.kind.name(),
+                if i + 1 < graph.edges.len() { "," } else { "" }
+            )?;
+        }
+
+        writeln!(self.dest, "  ]")?;
+        writeln!(self.dest, "}}")?;
+
+        Ok(self.dest.flush()?)
+    }
+}
+
+// quotes `s` as a JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+// quotes `s` as a DOT string, leaving escapes like `\n` for Graphviz to interpret
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\\\""))
+}
+
+// writes `:`, the record's bytes in hex and a checksum that makes them all sum to zero
+fn write_hex_record(dest: &mut dyn Write, record: &[u8]) -> std::io::Result<()> {
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+    write!(dest, ":")?;
+
+    for byte in record {
+        write!(dest, "{byte:02X}")?;
+    }
+
+    writeln!(dest, "{:02X}", sum.wrapping_neg())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(f: impl FnOnce(&mut Generator) -> Result<(), Box<dyn std::error::Error>>) -> String {
+        let program = crate::assemble("// sum\n(LOOP)\n@i\nM=M+1\n@LOOP\n0;JMP\n").unwrap();
+        let mut out = vec![];
+
+        f(&mut Generator::new(Box::new(&mut out), &program)).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn listing_shows_addresses_words_and_source() {
+        let listing = render(|generator| generator.write_listing());
+        let lines: Vec<_> = listing.lines().collect();
+
+        assert_eq!(lines[1], "                                   1  // sum");
+        assert_eq!(lines[2], "    0                              2  (LOOP)");
+        assert_eq!(lines[3], "    0  0000000000010000  0010      3  @i");
+        assert_eq!(lines[4], "    1  1111110111001000  FDC8      4  M=M+1");
+    }
+
+    #[test]
+    fn symbols_are_split_by_kind() {
+        let symbols = render(|generator| generator.write_symbols());
+
+        assert!(symbols.contains("// labels (ROM)\nLOOP                         0\n"));
+        assert!(symbols.contains("// variables (RAM)\ni                           16\n"));
+        assert!(symbols.starts_with("// predefined symbols\nR0                           0\n"));
+    }
+
+    #[test]
+    fn source_map_points_every_address_at_its_line() {
+        let map = render(|generator| generator.write_source_map());
+        let lines: Vec<_> = map.lines().collect();
+
+        assert_eq!(lines[2], "  \"files\": [\"<input>\"],");
+        assert_eq!(
+            lines[4],
+            "    {\"address\": 0, \"file\": 0, \"line\": 3, \"column\": 1, \"end_column\": 3, \
+             \"label\": \"LOOP\"},"
+        );
+        assert_eq!(lines.len(), 10);
+    }
+
+    #[test]
+    fn graphs_show_blocks_and_jumps() {
+        let dot = render(|generator| generator.write_graph(GraphFormat::Dot));
+        let json = render(|generator| generator.write_graph(GraphFormat::Json));
+
+        assert!(
+            dot.contains("  b0 [label=\"LOOP\\nROM[0]-ROM[3]\"];\n  b0 -> b0 [label=\"JMP\"];\n")
+        );
+        assert!(json.contains(
+            "\"blocks\": [\n    {\"id\": 0, \"start\": 0, \"end\": 4, \"labels\": [\"LOOP\"]}\n"
+        ));
+        assert!(json.contains("{\"from\": 0, \"to\": 0, \"kind\": \"jump\", \"jump\": \"JMP\"}"));
+    }
+
+    #[test]
+    fn writes_intel_hex_records() {
+        let hex = render(|generator| generator.write(Format::IntelHex));
+
+        assert_eq!(hex, ":080000000010FDC80000EA87B2\n:00000001FF\n");
+    }
+
+    #[test]
+    fn writes_memory_initialization_files() {
+        let mif = render(|generator| generator.write(Format::Mif));
+        let coe = render(|generator| generator.write(Format::Coe));
+
+        assert!(mif.starts_with("WIDTH=16;\nDEPTH=4;\n"));
+        assert!(mif.contains("\t1 : 1111110111001000;\n"));
+        assert!(mif.ends_with("END;\n"));
+        assert!(coe.ends_with("0000000000000000,\n1110101010000111;\n"));
+    }
+}
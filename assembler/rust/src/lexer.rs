@@ -0,0 +1,705 @@
+// this started out as an attempt at the strategy Rob Pike demonstrated in this video:
+//
+// https://www.youtube.com/watch?v=HxaD_trXwRE
+//
+// the lexer was a set of states, each a type implementing LexerState, whose scan method emitted
+// tokens into a channel and returned the next state in a Box. that was a fun way to learn the
+// idea, but it allocated on every transition and sent every token through a sync_channel, which
+// made large generated files (the Jack OS translated to asm is tens of thousands of lines) much
+// slower to assemble than they should be.
+//
+// the states are still there, but now they're variants of State and each one is a method that
+// either returns a token or moves on to the next state without one. Lexer is an Iterator that
+// runs states until one of them produces a token, and tokens borrow their text from the source,
+// so lexing doesn't allocate at all unless an operand is an expression (or there's an error).
+//
+// errors are reported through lexer.error, which records a Diagnostic, skips the rest of the
+// line, and returns Token::Invalid so the parser can throw away the partial instruction. lexing
+// then carries on with the next line, so every problem in a file is reported in a single run.
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expr::Expr;
+use crate::tokens::{CompToken, DestToken, JumpToken, Layout, Operand, Token};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    InstructionStart,
+    Comp,
+    AfterComp,
+    Jump,
+    InstructionEnd,
+    Done,
+}
+
+/// Splits Hack assembly into [`Token`]s without copying it. Spans of the tokens refer to file 0;
+/// the parser maps them back to the files they came from.
+pub struct Lexer<'a> {
+    source: &'a str,
+    position: usize,
+    state: State,
+    row: usize,
+    col: usize,
+    start_col: usize,
+    span: Span,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            position: 0,
+            state: State::InstructionStart,
+            row: 1,
+            col: 1,
+            start_col: 1,
+            span: Span::new(1, 1, 1),
+            diagnostics: vec![],
+        }
+    }
+
+    /// The span of the token most recently returned by next.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The problems found so far. Each one was also returned as a [`Token::Invalid`].
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn into_diagnostics(self) -> Vec<Diagnostic> {
+        self.diagnostics
+    }
+
+    // records an error covering the rest of the current word, then skips to the end of the line
+    // and returns Token::Invalid so the parser drops whatever it has of the current instruction.
+    fn error(&mut self, message: String, hint: Option<&str>) -> Option<Token<'a>> {
+        let start = self.col;
+
+        while let Some(byte) = self.current_byte() {
+            if matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b';' | b'/' | b')') {
+                break;
+            }
+            self.advance(1);
+        }
+
+        // always underline at least one column, even at the end of a line
+        let end = if self.col > start {
+            self.col
+        } else {
+            start + 1
+        };
+
+        self.error_at(Span::new(self.row, start, end), message, hint)
+    }
+
+    fn error_at(&mut self, span: Span, message: String, hint: Option<&str>) -> Option<Token<'a>> {
+        let mut diagnostic = Diagnostic::error(message, span);
+
+        if let Some(hint) = hint {
+            diagnostic = diagnostic.with_hint(hint);
+        }
+
+        self.diagnostics.push(diagnostic);
+
+        while let Some(byte) = self.current_byte() {
+            if byte == b'\n' {
+                break;
+            }
+            self.advance(1);
+        }
+
+        self.state = State::InstructionEnd;
+        Some(Token::Invalid)
+    }
+
+    fn current_byte(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.position).copied()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position..]
+    }
+
+    fn advance(&mut self, n: usize) {
+        for &byte in &self.source.as_bytes()[self.position..self.position + n] {
+            if byte == b'\n' {
+                self.row += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
+
+        self.position += n;
+    }
+
+    fn accept_str(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.advance(s.len());
+            true
+        } else {
+            false
+        }
+    }
+
+    // the text from the current position for as long as `predicate` holds, without consuming it
+    fn peek_while(&self, predicate: impl Fn(u8) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .bytes()
+            .position(|byte| !predicate(byte))
+            .unwrap_or(rest.len());
+
+        &rest[..len]
+    }
+
+    fn accept_while(&mut self, predicate: impl Fn(u8) -> bool) -> &'a str {
+        let text = self.peek_while(predicate);
+        self.advance(text.len());
+        text
+    }
+
+    // digits followed by a colon, like `1:`
+    fn at_numeric_label(&self) -> bool {
+        let digits = self.peek_while(|byte| byte.is_ascii_digit());
+
+        !digits.is_empty() && self.rest()[digits.len()..].starts_with(':')
+    }
+
+    fn accept_label(&mut self) -> Option<&'a str> {
+        // labels can start with a letter, underscore, or dollar sign
+        match self.current_byte() {
+            Some(b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'$') => Some(self.accept_while(is_label_byte)),
+            _ => None,
+        }
+    }
+
+    fn accept_spaces(&mut self) {
+        self.accept_while(|byte| matches!(byte, b' ' | b'\t'));
+    }
+
+    // everything up to a comment or the end of the line, without trailing whitespace
+    fn accept_operand(&mut self) -> &'a str {
+        let rest = self.rest();
+        let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+        let code = &line[..comment_start(line).unwrap_or(line.len())];
+        let operand = code.trim_end_matches([' ', '\t', '\r']);
+
+        self.advance(operand.len());
+        operand
+    }
+
+    // parses an operand as an expression, reporting problems at the right column
+    fn accept_expr(&mut self) -> Result<Expr, Option<Token<'a>>> {
+        let start = self.col;
+        let operand = self.accept_operand();
+
+        Expr::parse(operand).map_err(|e| {
+            let col = start + e.offset;
+            self.error_at(Span::new(self.row, col, col + 1), e.message, None)
+        })
+    }
+
+    fn lex_instruction_start(&mut self) -> Option<Token<'a>> {
+        self.accept_while(|byte| matches!(byte, b' ' | b'\t' | b'\r' | b'\n'));
+        self.start_col = self.col;
+
+        match self.current_byte() {
+            None => {
+                self.state = State::Done;
+                None
+            }
+            Some(b'/') if self.accept_str("//") => {
+                self.accept_while(|byte| byte != b'\n');
+                None
+            }
+            Some(b'@') => {
+                self.advance(1);
+                self.lex_address()
+            }
+            Some(b'(') => {
+                self.advance(1);
+                self.lex_label()
+            }
+            Some(b'.') => {
+                self.advance(1);
+                self.lex_directive()
+            }
+            Some(b'0'..=b'9') if self.at_numeric_label() => self.lex_numeric_label(),
+            Some(_) => self.lex_dest(),
+        }
+    }
+
+    fn lex_address(&mut self) -> Option<Token<'a>> {
+        self.state = State::InstructionEnd;
+        self.start_col = self.col;
+
+        if let Some(b' ' | b'\t' | b'\r' | b'\n' | b'/') | None = self.current_byte() {
+            return self.error(
+                "expected a label, address or expression after `@`".to_string(),
+                Some("e.g. `@42`, `@LOOP` or `@SCREEN+32`"),
+            );
+        }
+
+        let start = self.position;
+        let operand = self.accept_operand();
+
+        // plain numbers and labels are the common case, so they don't go through Expr. neither
+        // do references to numeric labels, like `@1b`, which aren't valid expressions
+        if !operand.is_empty() && operand.bytes().all(|byte| byte.is_ascii_digit()) {
+            if let Ok(addr) = operand.parse() {
+                return Some(Token::Addr(Operand::Number(addr)));
+            }
+        }
+
+        if is_symbol(operand) || is_numeric_reference(operand) {
+            return Some(Token::Addr(Operand::Symbol(operand)));
+        }
+
+        // go back and parse it properly
+        self.rewind(start);
+
+        match self.accept_expr() {
+            Ok(Expr::Number(addr)) if addr >= 0 => {
+                Some(Token::Addr(Operand::Number(addr as usize)))
+            }
+            Ok(expr) => Some(Token::Addr(Operand::Expr(expr))),
+            Err(token) => token,
+        }
+    }
+
+    // moves back to an earlier position on the current line
+    fn rewind(&mut self, position: usize) {
+        self.col -= self.position - position;
+        self.position = position;
+    }
+
+    fn lex_directive(&mut self) -> Option<Token<'a>> {
+        self.state = State::InstructionEnd;
+
+        match self.accept_label() {
+            Some("equ") => self.lex_equ(),
+            Some("org") => self.lex_layout(Layout::Org),
+            Some("fill") => self.lex_layout(Layout::Fill),
+            Some("align") => self.lex_layout(Layout::Align),
+            Some("data") => self.lex_data(),
+            Some("word") => self.lex_words(),
+            Some("string") => self.lex_string(),
+            Some(directive) => {
+                let end = self.col;
+                let start = end - directive.len() - 1;
+
+                self.error_at(
+                    Span::new(self.row, start, end),
+                    format!("unknown directive `.{directive}`"),
+                    None,
+                )
+            }
+            None => self.error("expected a directive after `.`".to_string(), None),
+        }
+    }
+
+    fn lex_equ(&mut self) -> Option<Token<'a>> {
+        self.accept_spaces();
+        // the definition's span starts at its name
+        self.start_col = self.col;
+
+        let Some(name) = self.accept_label() else {
+            return self.error(
+                "expected a name after `.equ`".to_string(),
+                Some("e.g. `.equ ROWS 256`"),
+            );
+        };
+
+        self.accept_spaces();
+
+        match self.accept_expr() {
+            Ok(expr) => Some(Token::Equ(name, expr)),
+            Err(token) => token,
+        }
+    }
+
+    fn lex_layout(&mut self, layout: Layout) -> Option<Token<'a>> {
+        self.accept_spaces();
+
+        match self.accept_expr() {
+            Ok(expr) => Some(Token::Layout(layout, expr)),
+            Err(token) => token,
+        }
+    }
+
+    fn lex_data(&mut self) -> Option<Token<'a>> {
+        self.accept_spaces();
+
+        match self.accept_label() {
+            Some(name) => Some(Token::Data(name)),
+            None => self.error(
+                "expected a name after `.data`".to_string(),
+                Some("e.g. `.data FONT`"),
+            ),
+        }
+    }
+
+    // comma-separated expressions
+    fn lex_words(&mut self) -> Option<Token<'a>> {
+        self.accept_spaces();
+
+        let start = self.col;
+        let operand = self.accept_operand();
+
+        if operand.is_empty() {
+            return self.error(
+                "expected values after `.word`".to_string(),
+                Some("e.g. `.word 1, 2, -1, SCREEN+32`"),
+            );
+        }
+
+        let mut words = vec![];
+        let mut offset = 0;
+
+        for value in operand.split(',') {
+            let leading = value.len() - value.trim_start().len();
+
+            match Expr::parse(value.trim()) {
+                Ok(expr) => words.push(expr),
+                Err(e) => {
+                    let col = start + offset + leading + e.offset;
+                    return self.error_at(Span::new(self.row, col, col + 1), e.message, None);
+                }
+            }
+
+            offset += value.len() + 1;
+        }
+
+        Some(Token::Words(words))
+    }
+
+    // a quoted string, which is stored a character to a word and ends with a 0
+    fn lex_string(&mut self) -> Option<Token<'a>> {
+        self.accept_spaces();
+
+        let start = self.col;
+        let operand = self.accept_operand();
+        let Some(text) = operand
+            .strip_prefix('"')
+            .and_then(|text| text.strip_suffix('"'))
+            .filter(|_| operand.len() >= 2)
+        else {
+            let span = Span::new(self.row, start, start + operand.len().max(1));
+            return self.error_at(
+                span,
+                "expected a quoted string after `.string`".to_string(),
+                Some("e.g. `.string \"GAME OVER\"`"),
+            );
+        };
+
+        let mut words = vec![];
+        let mut chars = text.char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            let c = match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    Some((_, '0')) => '\0',
+                    Some((_, c @ ('"' | '\\'))) => c,
+                    _ => {
+                        let col = start + 1 + i;
+                        return self.error_at(
+                            Span::new(self.row, col, col + 2),
+                            "unknown escape in string".to_string(),
+                            Some("strings can use \\n, \\t, \\0, \\\" and \\\\"),
+                        );
+                    }
+                },
+                '"' => {
+                    let col = start + 1 + i;
+                    return self.error_at(
+                        Span::new(self.row, col, col + 1),
+                        "unescaped `\"` in string".to_string(),
+                        Some("write it as \\\""),
+                    );
+                }
+                c => c,
+            };
+
+            words.push(Expr::Number(c as i64));
+        }
+
+        words.push(Expr::Number(0));
+        Some(Token::Words(words))
+    }
+
+    fn lex_label(&mut self) -> Option<Token<'a>> {
+        self.state = State::InstructionEnd;
+        self.start_col = self.col;
+
+        // local labels start with a `.`
+        let start = self.position;
+        self.accept_str(".");
+
+        if self.accept_label().is_none() {
+            return self.error(
+                "invalid character in label".to_string(),
+                Some("labels start with a letter, `_` or `$`"),
+            );
+        }
+
+        let label = &self.source[start..self.position];
+        let span = Span::new(self.row, self.start_col, self.col);
+
+        if !self.accept_str(")") {
+            return self.error(
+                "invalid character in label".to_string(),
+                Some("labels may only contain letters, digits, `_`, `.`, `$` and `-`"),
+            );
+        }
+
+        // the span covers the name, not the parentheses
+        self.span = span;
+        Some(Token::Label(label))
+    }
+
+    fn lex_numeric_label(&mut self) -> Option<Token<'a>> {
+        self.state = State::InstructionEnd;
+
+        let digits = self.peek_while(|byte| byte.is_ascii_digit());
+
+        let Ok(n) = digits.parse() else {
+            return self.error(format!("`{digits}` is too large for a numeric label"), None);
+        };
+
+        self.advance(digits.len() + 1);
+        Some(Token::NumericLabel(n))
+    }
+
+    fn lex_dest(&mut self) -> Option<Token<'a>> {
+        self.state = State::Comp;
+
+        let registers = self.peek_while(|byte| matches!(byte, b'A' | b'D' | b'M'));
+
+        // without an `=` the registers are the start of the computation
+        if !self.rest()[registers.len()..].starts_with('=') {
+            return Some(Token::Dest(DestToken::Empty));
+        }
+
+        let Some(dest) = DestToken::parse(registers) else {
+            let span = Span::new(self.row, self.col, self.col + registers.len());
+
+            return self.error_at(
+                span,
+                format!("`{registers}` is not a valid destination"),
+                Some("a destination names each of A, D and M at most once"),
+            );
+        };
+
+        self.advance(registers.len() + 1);
+        Some(Token::Dest(dest))
+    }
+
+    fn lex_comp(&mut self) -> Option<Token<'a>> {
+        let rest = self.rest();
+        // escapes like `%ALU(zx=1, nx=0, ...)` can have spaces inside their parentheses
+        let escape = match rest.find(['\n', ')']) {
+            Some(end) if rest.starts_with('%') && rest[end..].starts_with(')') => Some(end + 1),
+            _ => None,
+        };
+        let text = match escape {
+            Some(end) => &rest[..end],
+            None => {
+                self.peek_while(|byte| !matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b';' | b'/'))
+            }
+        };
+
+        let Some(comp) = CompToken::parse(text) else {
+            let hint = if text.starts_with('%') {
+                "e.g. `%comp(0b0000001)` or `%ALU(zx=0,nx=0,zy=0,ny=0,f=0,no=1)`, with `a=1` to use M"
+            } else {
+                "expected one of 0, 1, -1, D, A, M, !D, -D, D+1, D-1, D+A, D-A, A-D, D&A, D|A, ..."
+            };
+
+            return self.error("invalid computation".to_string(), Some(hint));
+        };
+
+        self.advance(text.len());
+        self.state = State::AfterComp;
+        Some(Token::Comp(comp))
+    }
+
+    fn lex_after_comp(&mut self) -> Option<Token<'a>> {
+        if self.accept_str(";") {
+            self.state = State::Jump;
+            None
+        } else {
+            self.state = State::InstructionEnd;
+            Some(Token::Jump(JumpToken::Empty))
+        }
+    }
+
+    fn lex_jump(&mut self) -> Option<Token<'a>> {
+        let jump = match self.peek_while(|byte| byte.is_ascii_uppercase()) {
+            "JGT" => JumpToken::JGT,
+            "JEQ" => JumpToken::JEQ,
+            "JGE" => JumpToken::JGE,
+            "JLT" => JumpToken::JLT,
+            "JNE" => JumpToken::JNE,
+            "JLE" => JumpToken::JLE,
+            "JMP" => JumpToken::JMP,
+            _ => {
+                return self.error(
+                    "invalid jump".to_string(),
+                    Some("expected one of JGT, JEQ, JGE, JLT, JNE, JLE or JMP"),
+                )
+            }
+        };
+
+        self.advance(3);
+        self.state = State::InstructionEnd;
+        Some(Token::Jump(jump))
+    }
+
+    fn lex_instruction_end(&mut self) -> Option<Token<'a>> {
+        self.accept_while(|byte| matches!(byte, b' ' | b'\t' | b'\r'));
+
+        if self.accept_str("//") {
+            self.accept_while(|byte| byte != b'\n');
+        }
+
+        match self.rest().chars().next() {
+            None | Some('\n') => {
+                self.state = State::InstructionStart;
+                None
+            }
+            Some(c) => self.error(
+                format!("unexpected character `{c}` after instruction"),
+                Some("comments must start with `//`"),
+            ),
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            self.start_col = self.col;
+
+            let token = match self.state {
+                State::InstructionStart => self.lex_instruction_start(),
+                State::Comp => self.lex_comp(),
+                State::AfterComp => self.lex_after_comp(),
+                State::Jump => self.lex_jump(),
+                State::InstructionEnd => self.lex_instruction_end(),
+                State::Done => return None,
+            };
+
+            if let Some(token) = token {
+                // labels set their own span, since they're lexed along with their parentheses
+                if !matches!(token, Token::Label(_)) {
+                    self.span = Span::new(self.row, self.start_col, self.col);
+                }
+
+                return Some(token);
+            }
+        }
+    }
+}
+
+fn is_label_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'.' | b'$' | b'-')
+}
+
+// a label, variable or constant, including local labels like `.loop`
+fn is_symbol(text: &str) -> bool {
+    matches!(
+        text.bytes().next(),
+        Some(b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'$' | b'.')
+    ) && text.bytes().all(is_label_byte)
+}
+
+// a reference to a numeric label, like `1b` or `2f`
+fn is_numeric_reference(text: &str) -> bool {
+    text.strip_suffix(['b', 'f']).is_some_and(|digits| {
+        !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit())
+    })
+}
+
+/// Where the `//` that starts the comment on `line` is, if there is one. `//` inside a quoted
+/// string, like `.string "a//b"`, doesn't start a comment.
+pub(crate) fn comment_start(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut quoted = false;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            // skip whatever's escaped, including `\"`
+            b'\\' if quoted => i += 1,
+            b'"' => quoted = !quoted,
+            b'/' if !quoted && bytes.get(i + 1) == Some(&b'/') => return Some(i),
+            _ => (),
+        }
+
+        i += 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simplest_instruction() -> Result<(), String> {
+        let mut lex = Lexer::new("0");
+        let dest = lex.next();
+        let comp = lex.next();
+        let jump = lex.next();
+        let eof = lex.next();
+        let actual = (dest, comp, jump, eof);
+
+        match actual {
+            (Some(Token::Dest(DestToken::Empty)), Some(Token::Comp(CompToken::Zero)), Some(Token::Jump(JumpToken::Empty)), None) => Ok(()),
+            _ => Err(format!("expected (Some(Dest(Empty)), Some(Comp(Zero)), Some(Jump(Empty)), None) but got {:?}", actual)),
+        }
+    }
+
+    #[test]
+    fn comments_start_outside_strings() {
+        let tokens: Vec<_> = Lexer::new(".string \"a//\\\"//\" // b\n").collect();
+        let words = "a//\"//\0".bytes().map(|byte| Expr::Number(byte as i64));
+
+        assert_eq!(tokens, [Token::Words(words.collect())]);
+    }
+
+    #[test]
+    fn rejects_non_ascii_operands() {
+        let diagnostics = crate::assemble("@é\n@1é\n@é1b\n").unwrap_err();
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(messages[0], "expected a number or symbol but found `é`");
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn tokens_borrow_from_the_source() {
+        let source = "(LOOP) // top\n@i\nAM=M+1;JGT\n@SCREEN+32\n";
+        let tokens: Vec<_> = Lexer::new(source).collect();
+
+        assert_eq!(
+            tokens,
+            [
+                Token::Label("LOOP"),
+                Token::Addr(Operand::Symbol("i")),
+                Token::Dest(DestToken::AM),
+                Token::Comp(CompToken::AddM1),
+                Token::Jump(JumpToken::JGT),
+                Token::Addr(Operand::Expr(Expr::parse("SCREEN+32").unwrap())),
+            ]
+        );
+    }
+}
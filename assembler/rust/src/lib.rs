@@ -0,0 +1,331 @@
+//! An assembler for the Hack machine language from the Nand to Tetris course.
+//!
+//! [`assemble`] turns Hack assembly held in memory into a [`Program`], so test harnesses, the VM
+//! translator and editors can share the same implementation as the CLI. [`assemble_files`] does
+//! the same for files on disk, assembling them one after the other as a single program.
+//!
+//! Libraries can be assembled once with [`assemble_object`] into relocatable [`Object`]s, which
+//! [`link`] combines into a program.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+pub mod cfg;
+mod control;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod expr;
+pub mod formatter;
+pub mod generator;
+pub mod lexer;
+pub mod linker;
+pub mod lint;
+pub mod object;
+mod optimizer;
+pub mod parser;
+mod preprocessor;
+pub mod source;
+pub mod tokens;
+
+pub use diagnostic::{Diagnostic, Diagnostics, Severity, Span};
+pub use linker::link;
+pub use object::Object;
+pub use parser::Parser;
+pub use source::{SourceFile, Sources};
+pub use tokens::{
+    AddrToken, CompToken, DestToken, Instruction, Isa, JumpToken, Layout, Operand, Token,
+};
+
+/// The result of assembling a source file.
+#[derive(Debug)]
+pub struct Program {
+    /// The decoded instructions in ROM order, with every address resolved.
+    pub instructions: Vec<Instruction>,
+    /// The final symbol table: predefined symbols, labels, constants and allocated variables.
+    pub symbols: HashMap<String, usize>,
+    /// The encoded machine words in ROM order.
+    pub words: Vec<u16>,
+    /// Where each instruction came from, in ROM order.
+    pub spans: Vec<Span>,
+    /// Where each label was defined.
+    pub labels: HashMap<String, Span>,
+    /// The variables in the order they were allocated, after the tables.
+    pub variables: Vec<String>,
+    /// The `.data` tables in the order they were allocated, starting at RAM[16].
+    pub tables: Vec<String>,
+    /// The `.equ` constants in the order they were defined. Those with a value that could be an
+    /// address are also in `symbols`.
+    pub constants: Vec<String>,
+    /// How many words the optimizer removed, when it was asked to run.
+    pub words_saved: usize,
+    /// Warnings about code that assembled but looks like a mistake.
+    pub warnings: Vec<Diagnostic>,
+    /// Every file that went into the program. Spans refer to files by their index in this table.
+    pub sources: Sources,
+}
+
+/// Settings that change how a program is assembled.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Run the peephole optimizer between the first and second passes.
+    pub optimize: bool,
+    /// Symbols defined from outside the program, like `-D NAME=value` on the command line. They
+    /// can be tested with `.ifdef` and `.if`, and used anywhere a predefined symbol can.
+    pub defines: Vec<(String, usize)>,
+    /// The instruction `.org`, `.fill` and `.align` pad ROM with. `None` pads with `0`, which
+    /// computes 0 without storing it or jumping, so it does nothing.
+    pub padding: Option<Instruction>,
+    /// Which instructions the program can use.
+    pub isa: Isa,
+}
+
+/// What a symbol in [`Program::symbols`] refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    Predefined,
+    Label,
+    Variable,
+    Table,
+    Constant,
+}
+
+impl Program {
+    fn from_parser(parser: Parser) -> Self {
+        let words = parser
+            .instructions
+            .iter()
+            .map(Instruction::encode)
+            .collect();
+
+        Self {
+            instructions: parser.instructions,
+            symbols: parser.symbols,
+            words,
+            spans: parser.spans,
+            labels: parser.labels,
+            variables: parser.variables,
+            tables: parser.tables.into_iter().map(|table| table.name).collect(),
+            constants: parser.constants,
+            words_saved: parser.words_saved,
+            warnings: parser.warnings,
+            sources: parser.sources,
+        }
+    }
+
+    pub fn symbol_kind(&self, name: &str) -> Option<SymbolKind> {
+        if self.labels.contains_key(name) {
+            Some(SymbolKind::Label)
+        } else if self.variables.iter().any(|variable| variable == name) {
+            Some(SymbolKind::Variable)
+        } else if self.tables.iter().any(|table| table == name) {
+            Some(SymbolKind::Table)
+        } else if self.constants.iter().any(|constant| constant == name) {
+            Some(SymbolKind::Constant)
+        } else if self.symbols.contains_key(name) {
+            Some(SymbolKind::Predefined)
+        } else {
+            None
+        }
+    }
+}
+
+/// Assembles Hack assembly held in memory. `.include` paths are relative to the current directory.
+pub fn assemble(source: &str) -> Result<Program, Diagnostics> {
+    assemble_sources(Sources::from(source))
+}
+
+/// Assembles the files at `paths` one after the other as a single program.
+pub fn assemble_files<P: AsRef<Path>>(
+    paths: &[P],
+) -> std::io::Result<Result<Program, Diagnostics>> {
+    let mut sources = Sources::new();
+
+    for path in paths {
+        sources.load(path.as_ref())?;
+    }
+
+    Ok(assemble_sources(sources))
+}
+
+/// Assembles every root file in `sources` one after the other as a single program.
+pub fn assemble_sources(sources: Sources) -> Result<Program, Diagnostics> {
+    assemble_with(sources, &Options::default())
+}
+
+/// Like [`assemble_sources`], with the behavior `options` asks for.
+pub fn assemble_with(sources: Sources, options: &Options) -> Result<Program, Diagnostics> {
+    let parser = Parser::with_options(sources, options);
+
+    if parser.diagnostics.is_empty() {
+        Ok(Program::from_parser(parser))
+    } else {
+        Err(Diagnostics {
+            diagnostics: parser.diagnostics,
+            sources: parser.sources,
+        })
+    }
+}
+
+/// Assembles every root file in `sources` into a relocatable object instead of a program.
+/// Labels stay relative to the start of the object, and names it doesn't define are left for
+/// the linker to resolve.
+pub fn assemble_object(sources: Sources, options: &Options) -> Result<Object, Diagnostics> {
+    let mut parser = Parser::unresolved(sources, options);
+
+    for table in &parser.tables {
+        parser.diagnostics.push(
+            Diagnostic::error("`.data` can't be used in an object".to_string(), table.span)
+                .with_hint("tables are filled in by code at the start of the program, which only exists once it's linked"),
+        );
+    }
+
+    // an object can end up anywhere in ROM, so it can't pin code to an address
+    for (layout, span, _) in &parser.layout {
+        if *layout != Layout::Fill {
+            parser.diagnostics.push(
+                Diagnostic::error(format!("`{layout}` can't be used in an object"), *span)
+                    .with_hint(
+                    "objects are placed in ROM when they're linked, so only `.fill` can pad them",
+                ),
+            );
+        }
+    }
+
+    let parser = parser.finish();
+
+    if parser.diagnostics.is_empty() {
+        Ok(Object::from_parser(&parser))
+    } else {
+        Err(Diagnostics {
+            diagnostics: parser.diagnostics,
+            sources: parser.sources,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_in_memory() {
+        let program = assemble("@2\nD=A\n(END)\n@END\n0;JMP\n").unwrap();
+
+        assert_eq!(program.words, vec![2, 0xec10, 2, 0xea87]);
+        assert_eq!(program.symbols["END"], 2);
+        assert_eq!(
+            program.instructions[1],
+            Instruction::Comp(DestToken::D, CompToken::A, JumpToken::Empty)
+        );
+    }
+
+    #[test]
+    fn labels_account_for_macro_expansions() {
+        let source = ".macro PUSHD\n@SP\nAM=M+1\nA=A-1\nM=D\n.endm\nPUSHD\n(END)\n@END\n0;JMP\n";
+        let program = assemble(source).unwrap();
+
+        assert_eq!(program.symbols["END"], 4);
+        assert_eq!(program.spans[3].line, 7);
+    }
+
+    #[test]
+    fn macro_errors_point_at_the_call_site() {
+        let diagnostics = assemble(".macro BAD\n0;JMX\n.endm\nBAD\n").unwrap_err();
+        let diagnostic = &diagnostics.diagnostics[0];
+
+        assert_eq!(diagnostic.span, Span::new(2, 3, 6));
+        assert_eq!(diagnostic.notes[0].0, Span::new(4, 1, 4));
+    }
+
+    #[test]
+    fn evaluates_constants_and_address_expressions() {
+        let source =
+            ".equ ROW 32\n.equ END_ROW END-1\n@SCREEN+ROW*5\n@END_ROW\n@0x4000\n(END)\n@0b1010\n";
+        let program = assemble(source).unwrap();
+
+        assert_eq!(program.words, vec![16384 + 160, 2, 0x4000, 10]);
+        assert_eq!(program.symbols["ROW"], 32);
+        assert_eq!(program.symbol_kind("END_ROW"), Some(SymbolKind::Constant));
+    }
+
+    #[test]
+    fn dashes_are_part_of_a_name_when_its_defined() {
+        let source = "(END-OF-PROGRAM)\n@END-OF-PROGRAM\n@SCREEN-1\n@i\n@i-1\n@x-y\n@SCREEN-ROW\n";
+        let program = assemble(&format!(".equ ROW 32\n{source}")).unwrap();
+
+        assert_eq!(program.words, vec![0, 16383, 16, 15, 17, 16384 - 32]);
+        assert_eq!(program.variables, ["i", "x-y"]);
+    }
+
+    #[test]
+    fn assembles_the_course_programs() {
+        // the programs from project 4 use labels like `(END-OF-PROGRAM)`
+        for name in ["fill/Fill", "mult/Mult"] {
+            let path = format!(
+                "{}/../../projects/04/{name}.asm",
+                env!("CARGO_MANIFEST_DIR")
+            );
+
+            assert!(assemble_files(&[path]).unwrap().is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn address_expressions_must_fit_in_15_bits() {
+        let diagnostics = assemble(".equ BIG 0x8000\n@BIG\n@1-2\n").unwrap_err();
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            "`BIG` evaluates to 32768, which doesn't fit in 15 bits"
+        );
+        assert_eq!(
+            messages[1],
+            "`1-2` evaluates to -1, which doesn't fit in 15 bits"
+        );
+    }
+
+    #[test]
+    fn rejects_programs_that_dont_fit() {
+        let diagnostics = assemble(&format!("@40000\n{}", "D=0\n".repeat(32768))).unwrap_err();
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(messages[0], "`@40000` doesn't fit in 15 bits");
+        assert_eq!(
+            messages[1],
+            "the program is 32769 words long, which doesn't fit in ROM"
+        );
+        assert_eq!(diagnostics.diagnostics[1].span.line, 32769);
+
+        let variables: String = (0..16369).map(|i| format!("@v{i}\n")).collect();
+        let diagnostics = assemble(&variables).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics.diagnostics[0].message,
+            "there's no room left in RAM for the variable `v16368`"
+        );
+    }
+
+    #[test]
+    fn resolves_local_and_numeric_labels() {
+        let source = "(MULT)\n(.loop)\n@.loop\n0;JMP\n(DIV)\n(.loop)\n1:\n@1f\nD;JEQ\n@1b\n0;JMP\n1:\n@MULT.loop\n@.loop\n";
+        let program = assemble(source).unwrap();
+
+        assert_eq!(program.words, vec![0, 0xea87, 6, 0xe302, 2, 0xea87, 0, 2]);
+        assert_eq!(program.symbols["DIV.loop"], 2);
+
+        let diagnostics = assemble("(MAIN)\n@.missing\n@2b\n@3f\n").unwrap_err();
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            [
+                "the local label `.missing` isn't defined here",
+                "there's no `2:` label before `@2b`",
+                "there's no `3:` label after `@3f`",
+            ]
+        );
+    }
+}
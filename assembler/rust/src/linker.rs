@@ -0,0 +1,243 @@
+// the linker lays objects out in ROM one after the other and moves each one's labels and
+// relocations by where it starts. everything else is resolved the same way as when a program is
+// assembled from source, by Parser::second_pass: constants are evaluated, names no object
+// defines become variables, allocated from RAM[16] upward across every object, and expressions
+// are evaluated last.
+//
+// `.equ` constants and the labels generated by macro expansions and control flow blocks, like
+// `SKIPZ.1$SKIP`, `if.1$else` or the `loop.1$count` variable, are local to the object they're in,
+// since every object numbers its expansions and blocks from 1. the linker tells
+// them apart with a `#n` suffix, where n counts the objects from 1, which can't clash with a name
+// in the source.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::diagnostic::{Diagnostic, Diagnostics, Span};
+use crate::expr::Expr;
+use crate::object::{Object, Site};
+use crate::parser::Parser;
+use crate::source::Sources;
+use crate::tokens::{AddrToken, Instruction, Isa};
+use crate::Program;
+
+/// Links `objects` into a program, in the order they're given.
+///
+/// Diagnostics quote the files the objects were assembled from when they can still be read.
+pub fn link(objects: &[Object]) -> Result<Program, Diagnostics> {
+    let mut sources = Sources::new();
+
+    for object in objects {
+        for file in &object.files {
+            sources.add(file, std::fs::read_to_string(file).unwrap_or_default());
+        }
+    }
+
+    let mut parser = Parser::empty(sources, &[]);
+    // numeric labels are numbered within each object, so later objects carry on from earlier ones
+    let mut numeric: HashMap<usize, usize> = HashMap::new();
+    let mut files = 0;
+
+    for (k, object) in objects.iter().enumerate() {
+        let base = parser.instructions.len();
+        let locate = |span: Span| span.in_file(span.file + files);
+        let mut defined: HashMap<usize, usize> = HashMap::new();
+
+        let constants: HashSet<&str> = object
+            .constants
+            .iter()
+            .map(|(name, _, _)| name.as_str())
+            .collect();
+        let local = |name: &str| match constants.contains(name) || is_generated(name) {
+            true => format!("{name}#{}", k + 1),
+            false => name.to_string(),
+        };
+
+        for (name, offset, span) in &object.labels {
+            let name = match name
+                .split_once(':')
+                .map(|(n, i)| (n.parse::<usize>(), i.parse::<usize>()))
+            {
+                Some((Ok(n), Ok(i))) => {
+                    let count = defined.entry(n).or_default();
+                    *count = (*count).max(i + 1);
+                    format!("{n}:{}", numeric.get(&n).copied().unwrap_or(0) + i)
+                }
+                _ => local(name),
+            };
+
+            let span = locate(*span);
+
+            if let Some(diagnostic) = redefinition(&parser, &name, span) {
+                parser.diagnostics.push(diagnostic);
+            } else {
+                parser.labels.insert(name.clone(), span);
+                parser.symbols.insert(name, base + offset);
+            }
+        }
+
+        for (n, count) in defined {
+            *numeric.entry(n).or_default() += count;
+        }
+
+        for (name, expr, span) in &object.constants {
+            let name = local(name);
+            let span = locate(*span);
+
+            if let Some(diagnostic) = redefinition(&parser, &name, span) {
+                parser.diagnostics.push(diagnostic);
+            } else {
+                parser.constants.push(name.clone());
+                parser
+                    .definitions
+                    .insert(name, (rename(expr, &local), span));
+            }
+        }
+
+        for (site, span) in &object.code {
+            let span = locate(*span);
+            let instruction = match site {
+                // objects were checked against their instruction set when they were assembled
+                Site::Word(word) => match Instruction::decode_for(*word, Isa::Extended) {
+                    Some(instruction) => instruction,
+                    None => {
+                        parser.diagnostics.push(Diagnostic::error(
+                            format!("{word:016b} is not a valid instruction"),
+                            span,
+                        ));
+                        continue;
+                    }
+                },
+                Site::Relocation(offset) => Instruction::Addr(AddrToken::Static(base + offset)),
+                Site::External(name) => Instruction::Addr(AddrToken::Dynamic(local(name))),
+                Site::Expr(expr) => Instruction::Addr(AddrToken::Expr(rename(expr, &local))),
+            };
+
+            parser.instructions.push(instruction);
+            parser.spans.push(span);
+        }
+
+        files += object.files.len();
+    }
+
+    parser.split_names();
+    parser.check_rom_size();
+
+    if parser.diagnostics.is_empty() {
+        parser = parser.second_pass();
+    }
+
+    let parser = parser.finish();
+
+    if parser.diagnostics.is_empty() {
+        Ok(Program::from_parser(parser))
+    } else {
+        Err(Diagnostics {
+            diagnostics: parser.diagnostics,
+            sources: parser.sources,
+        })
+    }
+}
+
+// whether `name` was generated by a macro expansion or a control flow block, like `SKIPZ.1$SKIP`
+// or `loop.1$count`
+fn is_generated(name: &str) -> bool {
+    name.split_once('$').is_some_and(|(prefix, _)| {
+        prefix
+            .rsplit_once('.')
+            .is_some_and(|(_, n)| !n.is_empty() && n.bytes().all(|byte| byte.is_ascii_digit()))
+    })
+}
+
+// `expr` with every symbol in it renamed by `local`
+fn rename(expr: &Expr, local: &impl Fn(&str) -> String) -> Expr {
+    match expr {
+        Expr::Number(_) => expr.clone(),
+        Expr::Symbol(name) => Expr::Symbol(local(name)),
+        Expr::Neg(operand) => Expr::Neg(Box::new(rename(operand, local))),
+        Expr::Binary(op, lhs, rhs) => Expr::Binary(
+            *op,
+            Box::new(rename(lhs, local)),
+            Box::new(rename(rhs, local)),
+        ),
+    }
+}
+
+// an error if `name` is already a label or constant in an earlier object
+fn redefinition(parser: &Parser, name: &str, span: Span) -> Option<Diagnostic> {
+    let first = match parser.labels.get(name) {
+        Some(first) => *first,
+        None => parser.definitions.get(name)?.1,
+    };
+
+    let file = parser.sources.name(first.file);
+    let diagnostic = if parser.labels.contains_key(name) {
+        Diagnostic::error(format!("the ({name}) label is defined twice"), span)
+    } else {
+        Diagnostic::error(format!("`{name}` is already defined"), span)
+    };
+
+    Some(
+        diagnostic
+            .with_hint(format!("first defined in {file}"))
+            .with_note(first, "first defined here"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble_object, Options};
+
+    fn object(name: &str, source: &str) -> Object {
+        let mut sources = Sources::new();
+        sources.add(name, source);
+        assemble_object(sources, &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn links_objects_into_one_program() {
+        let main = object("Main.asm", "@count\nM=0\n1:\n@MULT\n0;JMP\n@1b\n");
+        let math = object(
+            "Math.asm",
+            "(MULT)\n@count\nM=M+1\n@temp\n1:\n@1b\n(END)\n@END\n",
+        );
+        let program = link(&[main, math]).unwrap();
+
+        assert_eq!(
+            program.words,
+            [16, 0xea88, 5, 0xea87, 2, 16, 0xfdc8, 17, 8, 9]
+        );
+        assert_eq!(program.variables, ["count", "temp"]);
+        assert_eq!(program.symbols["MULT"], 5);
+        assert_eq!(program.symbols["1:1"], 8);
+    }
+
+    #[test]
+    fn keeps_constants_and_generated_labels_local_to_each_object() {
+        let source = ".equ ROWS 2\n.macro SKIPZ\n@SKIP\nD;JEQ\n(SKIP)\n.endm\n\
+                      SKIPZ\n.loop ROWS\n.if D>0\n.endif\n.endloop\n@ROWS\n";
+        let first = object("A.asm", source);
+        let second = object("B.asm", &source.replace(".equ ROWS 2", ".equ ROWS 3"));
+        let program = link(&[first, second]).unwrap();
+
+        assert_eq!(program.symbols["SKIPZ.1$SKIP#2"], 19);
+        assert_eq!(program.symbols["if.2$else#2"], 29);
+        assert_eq!(program.variables, ["loop.1$count#1", "loop.1$count#2"]);
+        assert_eq!(program.words[16], 2);
+        assert_eq!(program.words[33], 3);
+    }
+
+    #[test]
+    fn rejects_labels_defined_in_two_objects() {
+        let first = object("A.asm", "(START)\n@START\n");
+        let second = object("B.asm", "(START)\n@START\n");
+        let diagnostics = link(&[first, second]).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics.diagnostics[0].message,
+            "the (START) label is defined twice"
+        );
+        assert_eq!(diagnostics.diagnostics[0].span.file, 1);
+    }
+}
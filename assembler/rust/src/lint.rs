@@ -0,0 +1,282 @@
+// warnings about programs that assemble but probably don't do what was meant. the lints run
+// once every symbol has been resolved, and each one has an id that a `// hack-allow(id)`
+// comment suppresses, either on the line the warning points at or alone on the line before it.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::cfg;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::comment_start;
+use crate::parser::{Parser, PREDEFINED_SYMBOLS};
+use crate::source::Sources;
+use crate::tokens::{CompToken, DestToken, Instruction, JumpToken};
+
+/// Every lint, by id, with a description of what it warns about.
+pub const LINTS: [(&str, &str); 6] = [
+    ("unused-label", "a label that nothing refers to"),
+    (
+        "shadowed-symbol",
+        "a label that differs from a predefined symbol only in case, like `sp` or `r13`",
+    ),
+    (
+        "single-use-variable",
+        "a variable that's only referred to once, which is often a misspelled label",
+    ),
+    (
+        "label-as-ram-address",
+        "a ROM label loaded into A and then used to access RAM through M",
+    ),
+    (
+        "unreachable-code",
+        "an instruction after an unconditional jump that no label or known jump target leads to",
+    ),
+    (
+        "a-write-m-read",
+        "a C-instruction that reads M and writes both A and M, where M is written at the address \
+         A held before the instruction (`AM=M+1` and `AM=M-1`, which move a pointer, are fine)",
+    ),
+];
+
+pub(crate) fn run(parser: &Parser) -> Vec<Diagnostic> {
+    let mut warnings = vec![];
+    let mut uses: HashMap<&str, Vec<usize>> = HashMap::new();
+
+    for (i, names) in parser.references.iter().enumerate() {
+        for name in names {
+            uses.entry(name.as_str()).or_default().push(i);
+        }
+    }
+
+    let in_constants: HashSet<&str> = parser
+        .definitions
+        .values()
+        .flat_map(|(expr, _)| expr.symbols())
+        .collect();
+
+    let mut labels: Vec<_> = parser.labels.iter().collect();
+    labels.sort_by_key(|(_, span)| **span);
+
+    for (label, span) in labels {
+        if !uses.contains_key(label.as_str()) && !in_constants.contains(label.as_str()) {
+            warnings.push(Diagnostic::warning(
+                "unused-label",
+                format!("{} is never used", describe(label)),
+                *span,
+            ));
+        }
+
+        let predefined = PREDEFINED_SYMBOLS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(label));
+
+        if let Some((name, _)) = predefined {
+            warnings.push(
+                Diagnostic::warning(
+                    "shadowed-symbol",
+                    format!("the ({label}) label looks like the predefined symbol `{name}`"),
+                    *span,
+                )
+                .with_hint("symbols are case sensitive, so this is a new label"),
+            );
+        }
+    }
+
+    for variable in &parser.variables {
+        if let Some(&[i]) = uses.get(variable.as_str()).map(Vec::as_slice) {
+            warnings.push(
+                Diagnostic::warning(
+                    "single-use-variable",
+                    format!("the variable `{variable}` is only used once"),
+                    parser.spans[i],
+                )
+                .with_hint("if it's meant to be a label, check its spelling"),
+            );
+        }
+    }
+
+    // the same entry points the control-flow graph starts blocks at
+    let labels = parser.labels.keys().map(|label| parser.symbols[label]);
+    let targets = cfg::entries(&parser.instructions, labels);
+
+    for (i, instruction) in parser.instructions.iter().enumerate() {
+        // padding is never meant to run
+        if parser.is_padding(i) {
+            continue;
+        }
+
+        let next = parser.instructions.get(i + 1);
+
+        match instruction {
+            Instruction::Addr(_) => {
+                let label = match parser.references[i].as_slice() {
+                    [name] if parser.labels.contains_key(name) => name,
+                    _ => continue,
+                };
+
+                if let Some(Instruction::Comp(dest, comp, _)) = next {
+                    if writes_m(dest) || reads_m(comp) {
+                        warnings.push(
+                            Diagnostic::warning(
+                                "label-as-ram-address",
+                                format!("`{label}` is a ROM address but it's used to access RAM"),
+                                parser.spans[i],
+                            )
+                            .with_note(parser.spans[i + 1], "M is accessed here"),
+                        );
+                    }
+                }
+            }
+
+            Instruction::Comp(dest, comp, jump) => {
+                // `AM=M+1` and `AM=M-1` are how a stack pointer is moved and followed at once
+                let moves_pointer = matches!(comp, CompToken::AddM1 | CompToken::SubM1);
+
+                if writes_a(dest) && writes_m(dest) && reads_m(comp) && !moves_pointer {
+                    warnings.push(
+                        Diagnostic::warning(
+                            "a-write-m-read",
+                            format!("`{instruction}` writes A and reads M"),
+                            parser.spans[i],
+                        )
+                        .with_hint("M is read from the address A held before the instruction"),
+                    );
+                }
+
+                if *jump == JumpToken::JMP
+                    && next.is_some()
+                    && !targets.contains(&(i + 1))
+                    && !parser.is_padding(i + 1)
+                {
+                    warnings.push(
+                        Diagnostic::warning(
+                            "unreachable-code",
+                            "unreachable instruction".to_string(),
+                            parser.spans[i + 1],
+                        )
+                        .with_note(parser.spans[i], "the instruction before always jumps"),
+                    );
+                }
+            }
+        }
+    }
+
+    let allowed = Allowed::scan(&parser.sources);
+    warnings.retain(|warning| !allowed.contains(warning));
+    warnings
+}
+
+// numeric labels are known internally as `1:0`, `1:1` and so on
+fn describe(label: &str) -> String {
+    match label.split_once(':') {
+        Some((n, _)) => format!("the `{n}:` label"),
+        None => format!("the ({label}) label"),
+    }
+}
+
+fn writes_a(dest: &DestToken) -> bool {
+    dest.code() & 0b100 != 0
+}
+
+fn writes_m(dest: &DestToken) -> bool {
+    dest.code() & 0b001 != 0
+}
+
+fn reads_m(comp: &CompToken) -> bool {
+    comp.code() & 0b1000000 != 0
+}
+
+// the lints each line allows, and whether the line is nothing but a comment
+struct Allowed {
+    lines: HashMap<(usize, usize), (Vec<String>, bool)>,
+}
+
+impl Allowed {
+    fn scan(sources: &Sources) -> Self {
+        let mut lines = HashMap::new();
+
+        for (file, source) in sources.iter() {
+            for (n, text) in source.text.lines().enumerate() {
+                let Some(comment) = comment_start(text) else {
+                    continue;
+                };
+
+                let mut ids = vec![];
+                let mut rest = &text[comment..];
+
+                while let Some(start) = rest.find("hack-allow(") {
+                    rest = &rest[start + "hack-allow(".len()..];
+
+                    let Some(end) = rest.find(')') else {
+                        break;
+                    };
+
+                    ids.extend(rest[..end].split(',').map(|id| id.trim().to_string()));
+                    rest = &rest[end..];
+                }
+
+                if !ids.is_empty() {
+                    let comment_only = text[..comment].trim().is_empty();
+                    lines.insert((file, n + 1), (ids, comment_only));
+                }
+            }
+        }
+
+        Self { lines }
+    }
+
+    fn contains(&self, warning: &Diagnostic) -> bool {
+        let Span { file, line, .. } = warning.span;
+        let allows = |line, alone: bool| match self.lines.get(&(file, line)) {
+            Some((ids, comment_only)) => {
+                (*comment_only || !alone) && ids.iter().any(|id| Some(id.as_str()) == warning.id)
+            }
+            None => false,
+        };
+
+        allows(line, false) || allows(line - 1, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn lints(source: &str) -> Vec<&'static str> {
+        let program = crate::assemble(source).unwrap();
+        program
+            .warnings
+            .iter()
+            .filter_map(|warning| warning.id)
+            .collect()
+    }
+
+    #[test]
+    fn warns_about_suspicious_code() {
+        assert_eq!(lints("(UNUSED)\n@0\nD=A\n"), ["unused-label"]);
+        assert_eq!(lints("(sp)\n@sp\n0;JMP\n"), ["shadowed-symbol"]);
+        assert_eq!(
+            lints("@count\nM=0\n@count\nM=M+1\n@typo\n"),
+            ["single-use-variable"]
+        );
+        assert_eq!(
+            lints("(LOOP)\n@LOOP\nM=0\n@LOOP\n0;JMP\n"),
+            ["label-as-ram-address"]
+        );
+        assert_eq!(lints("@0\n0;JMP\nD=0\n"), ["unreachable-code"]);
+        assert_eq!(lints("@SP\nAM=D+M\nA=M\n"), ["a-write-m-read"]);
+    }
+
+    #[test]
+    fn leaves_common_idioms_alone() {
+        assert!(lints("@SP\nAM=M-1\nD=M\n@SP\nAM=M+1\nAD=M\n").is_empty());
+        assert!(lints("@3\n0;JMP\nD=0\nD=1\n@2\n0;JMP\n").is_empty());
+    }
+
+    #[test]
+    fn hack_allow_comments_suppress_warnings() {
+        assert!(lints("(UNUSED) // hack-allow(unused-label)\n").is_empty());
+        assert!(lints("@SP\n// hack-allow(unused-label, a-write-m-read)\nAM=D+M\n").is_empty());
+        assert_eq!(
+            lints("@SP // hack-allow(a-write-m-read)\nAM=D+M\n"),
+            ["a-write-m-read"]
+        );
+    }
+}
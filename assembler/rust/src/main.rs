@@ -1,756 +1,734 @@
-// some if this looks the way it does because i was curious to try out the strategy Rob Pike
-// demonstrated in this video:
-//
-// https://www.youtube.com/watch?v=HxaD_trXwRE
-//
-// i didn't do a great job of emulating Rob's strategy. he used a start variable in his Lexer
-// struct to keep track of the length of the current token being lexed, i.e.,
-//
-//     pos - start = current_token_length
-//
-// which made his calls to emit really clean. if i did a better job of copying this, all the emit
-// calls should have just looked like:
-//
-//     lexer.emit(Token::...)
-//
-// which is mostly the case, but in LexAddress and LexLabel there's additional information beyond
-// just the token type being passed to emit. this also broke the uniformity of the accept_*
-// methods because acccept_label and accept_addr return Option<String>, and Option<usize>
-// respectively. All the other accept_* methods just return bool.
-//
-// a final criticism of this implementation is the error handling. I'm using panic! to bubble up
-// errors, but Rob used a wrapper funciton that would print a nicely formatted error before
-// returning "None" (which bailed out of the lexer). this shouldn't be difficult to replicate in
-// rust, but i got lazy.
-//
-// i don't think this is the best way, or even a good way, to express the ideas from Rob's video in
-// rust. i'd like to come back to this after i've read through some existing parser combinator
-// libraries and am more familiar with rust.
-
-use std::{
-    collections::HashMap,
-    sync::mpsc::{sync_channel, Receiver, SyncSender},
-};
-
-struct Lexer {
-    bytes: Vec<u8>,
-    position: usize,
-    state: Box<dyn LexerState>,
-    tx: SyncSender<Token>,
-    rx: Receiver<Token>,
-    row: usize,
-    col: usize,
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use assembler::cfg::GraphFormat;
+use assembler::expr::Expr;
+use assembler::generator::{Format, Generator};
+use assembler::parser::{PREDEFINED_SYMBOLS, ROM_SIZE};
+use assembler::{disassembler, formatter};
+use assembler::{Diagnostics, Instruction, Isa, Options, Program, Sources};
+
+const USAGE: &str = "\
+usage: asm <command> [options] <input>...
+
+commands:
+    build      assemble the inputs into a single program
+    disasm     turn a .hack (or raw binary) ROM image back into assembly
+    check      report errors and warnings without writing anything
+    symbols    print the program's symbol table
+    link       link relocatable objects built with `build -c` into a program
+    fmt        rewrite the inputs in the canonical style
+    cfg        print the program's control-flow graph
+
+options:
+    -o, --output <path>    where to write the output, or `-` for stdout
+    --format <format>      the machine-code format `build` writes (default: hack), or the
+                           graph format `cfg` writes: `dot` (the default) or `json`
+    --isa <isa>            `standard`, or `extended` for the shift instructions (D<<, A>> ...)
+    -c                     build a relocatable object (<stem>.hobj) instead of a program
+    -O                     run the peephole optimizer
+    -D <name>[=<value>]    define a symbol for conditional assembly (the value defaults to 1)
+    --pad <instruction>    what .org, .fill and .align pad ROM with (default: 0)
+    --listing              also write a <stem>.lst listing beside the output
+    --symbols              also write a <stem>.sym symbol file beside the output
+    --source-map           also write a <stem>.map.json source map beside the output
+    --check                with `fmt`, list the inputs that aren't formatted instead of
+                           rewriting them
+    -q, --quiet            only print errors
+    -h, --help             print this message
+
+an input of `-` reads from stdin. without -o, `build` writes <stem>.<ext> beside the first
+input (or to stdout when reading stdin), `fmt` rewrites its inputs in place (or writes stdin to
+stdout), and the other commands write to stdout.
+
+exit codes: 0 on success, 1 when the program has errors or a file can't be read or written,
+and 2 when the command line is wrong.";
+
+// exit codes scripts can rely on
+const EXIT_ERRORS: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Build,
+    Disasm,
+    Check,
+    Symbols,
+    Link,
+    Fmt,
+    Cfg,
 }
 
-impl Lexer {
-    fn from_str(s: &str) -> Self {
-        let (tx, rx) = sync_channel(2);
-
-        Self {
-            bytes: Vec::from(s.as_bytes()),
-            position: 0,
-            state: Box::new(LexInstructionStart),
-            tx,
-            rx,
-            row: 1,
-            col: 1,
-        }
-    }
+#[derive(Debug, PartialEq)]
+struct Args {
+    command: Command,
+    inputs: Vec<String>,
+    output: Option<String>,
+    format: Format,
+    graph: GraphFormat,
+    isa: Isa,
+    object: bool,
+    optimize: bool,
+    defines: Vec<(String, usize)>,
+    padding: Option<Instruction>,
+    listing: bool,
+    symbols: bool,
+    source_map: bool,
+    check: bool,
+    quiet: bool,
+}
 
-    fn emit(&self, token: Token) {
-        self.tx.send(token).unwrap();
-    }
+// something that stops a command, reported as `error: ...`
+type Failure = Box<dyn std::error::Error>;
 
-    fn current_byte(&self) -> Option<u8> {
-        if self.position < self.bytes.len() {
-            Some(self.bytes[self.position])
-        } else {
-            None
-        }
-    }
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-    fn current_char(&self) -> Option<char> {
-        self.current_byte().map(|byte| byte as char)
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
     }
 
-    fn next_token(&mut self) -> Option<Token> {
-        loop {
-            if let Ok(token) = self.rx.try_recv() {
-                // println!("returning token: {token:?}");
-                return Some(token);
-            }
-
-            let state = std::mem::replace(&mut self.state, Box::new(LexInstructionStart));
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("error: {message}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-            if let Some(next_state) = state.scan(self) {
-                self.state = next_state;
-                continue;
-            }
+    let result = match args.command {
+        Command::Build => build(&args),
+        Command::Disasm => disasm(&args),
+        Command::Check => check(&args),
+        Command::Symbols => symbols(&args),
+        Command::Link => link(&args),
+        Command::Fmt => fmt(&args),
+        Command::Cfg => cfg(&args),
+    };
 
-            return None;
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::from(EXIT_ERRORS)
         }
     }
+}
 
-    fn advance(&mut self, n: usize) -> bool {
-        let new_position = self.position + n;
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command, rest),
+        None => return Err("no command given".to_string()),
+    };
+
+    let command = match command.as_str() {
+        "build" => Command::Build,
+        "disasm" => Command::Disasm,
+        "check" => Command::Check,
+        "symbols" => Command::Symbols,
+        "link" => Command::Link,
+        "fmt" => Command::Fmt,
+        "cfg" => Command::Cfg,
+        other => return Err(format!("unknown command `{other}`")),
+    };
+
+    let mut parsed = Args {
+        command,
+        inputs: vec![],
+        output: None,
+        format: Format::Hack,
+        graph: GraphFormat::Dot,
+        isa: Isa::Standard,
+        object: false,
+        optimize: false,
+        defines: vec![],
+        padding: None,
+        listing: false,
+        symbols: false,
+        source_map: false,
+        check: false,
+        quiet: false,
+    };
+
+    let mut rest = rest.iter();
+    let mut options_done = false;
+
+    while let Some(arg) = rest.next() {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            parsed.inputs.push(arg.clone());
+            continue;
+        }
+
+        // `-D NAME=value` and `-DNAME=value` are the same thing
+        if let Some(define) = arg.strip_prefix("-D") {
+            let define = match define {
+                "" => rest.next().ok_or("`-D` needs a name")?,
+                define => define,
+            };
+
+            parsed.defines.push(parse_define(define, &parsed.defines)?);
+            continue;
+        }
+
+        // `--name=value` and `--name value` are the same thing
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
 
-        if new_position > self.bytes.len() {
-            false
-        } else {
-            while self.position < new_position {
-                if let Some(b'\n') = self.current_byte() {
-                    self.row += 1;
-                    self.col = 1;
-                } else {
-                    self.col += 1;
-                }
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| rest.next().cloned())
+                .ok_or_else(|| format!("`{name}` needs a value"))
+        };
 
-                self.position += 1;
-            }
+        match name {
+            "--" => options_done = true,
+            "-o" | "--output" => parsed.output = Some(value()?),
+            "--format" if command == Command::Cfg => parsed.graph = value()?.parse()?,
+            "--format" => parsed.format = value()?.parse()?,
+            "--pad" => parsed.padding = Some(parse_padding(&value()?)?),
+            "--isa" => parsed.isa = value()?.parse()?,
+            "-c" => parsed.object = true,
+            "-O" => parsed.optimize = true,
+            "--listing" => parsed.listing = true,
+            "--symbols" => parsed.symbols = true,
+            "--source-map" => parsed.source_map = true,
+            "--check" => parsed.check = true,
+            "-q" | "--quiet" => parsed.quiet = true,
+            _ => return Err(format!("unknown option `{arg}`")),
+        }
 
-            true
+        if inline.is_some() && !matches!(name, "--output" | "--format" | "--pad" | "--isa") {
+            return Err(format!("`{name}` doesn't take a value"));
         }
     }
 
-    fn accept_str(&mut self, s: &str) -> bool {
-        let start = self.position;
-        let end = self.position + s.len();
-
-        if let Some(slice) = self.bytes.get(start..end) {
-            if slice == s.as_bytes() {
-                return self.advance(s.len());
-            }
-        }
+    if parsed.inputs.is_empty() {
+        return Err("no input files".to_string());
+    }
 
-        false
+    if parsed.inputs.iter().filter(|input| *input == "-").count() > 1 {
+        return Err("stdin can only be read once".to_string());
     }
 
-    fn accept_any(&mut self) -> bool {
-        match self.current_byte() {
-            Some(_) => self.advance(1),
-            _ => false,
-        }
+    if command == Command::Disasm && parsed.inputs.len() > 1 {
+        return Err("`disasm` takes a single ROM image".to_string());
     }
 
-    fn accept_whitespace(&mut self) -> bool {
-        match self.current_byte() {
-            Some(b' ' | b'\t' | b'\r' | b'\n') => self.advance(1),
-            _ => false,
+    // the options that only apply to writing a program
+    let program_only = [
+        (parsed.format != Format::Hack, "--format"),
+        (parsed.listing, "--listing"),
+        (parsed.symbols, "--symbols"),
+        (parsed.source_map, "--source-map"),
+    ];
+
+    if let Some((_, flag)) = program_only.iter().find(|(set, _)| *set) {
+        if !matches!(command, Command::Build | Command::Link) {
+            return Err(format!("`{flag}` only applies to `build` and `link`"));
         }
-    }
 
-    fn accept_non_eol_whitespace(&mut self) -> bool {
-        match self.current_byte() {
-            Some(b' ' | b'\r' | b'\t') => self.advance(1),
-            _ => false,
+        if parsed.object {
+            return Err(format!("`{flag}` can't be used with `-c`"));
         }
     }
 
-    fn accept_eol(&mut self) -> bool {
-        match self.current_byte() {
-            Some(b'\n') => self.advance(1),
-            _ => false,
-        }
+    if parsed.object && command != Command::Build {
+        return Err("`-c` only applies to `build`".to_string());
     }
 
-    fn accept_label(&mut self) -> Option<String> {
-        let mut label = String::new();
+    if parsed.optimize && command == Command::Link {
+        return Err("`-O` applies when assembling, not linking".to_string());
+    }
 
-        // labels can start with a letter, underscore, or dollar sign
-        match self.current_byte() {
-            Some(b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'$') => (),
-            _ => return None,
-        };
+    if parsed.isa != Isa::Standard && command == Command::Link {
+        return Err("`--isa` applies when assembling or disassembling, not linking".to_string());
+    }
 
-        while let Some(
-            byte @ b'A'..=b'Z'
-            | byte @ b'a'..=b'z'
-            | byte @ b'0'..=b'9'
-            | byte @ b'_'
-            | byte @ b'.'
-            | byte @ b'$'
-            | byte @ b'-',
-        ) = self.current_byte()
-        {
-            label.push(byte as char);
-            self.advance(1);
-        }
+    let assembling = [
+        (!parsed.defines.is_empty(), "-D"),
+        (parsed.padding.is_some(), "--pad"),
+    ];
 
-        if !label.is_empty() {
-            Some(label)
-        } else {
-            None
+    if let Some((_, flag)) = assembling.iter().find(|(set, _)| *set) {
+        if matches!(command, Command::Disasm | Command::Link) {
+            return Err(format!("`{flag}` only applies when assembling"));
         }
     }
 
-    fn accept_addr(&mut self) -> Option<usize> {
-        let mut number = String::new();
+    // listings, symbol files and source maps go beside the output, so there has to be a file to
+    // put them by
+    let to_stdout = match &parsed.output {
+        Some(output) => output == "-",
+        None => parsed.inputs[0] == "-",
+    };
 
-        while let Some(byte @ b'0'..=b'9') = self.current_byte() {
-            number.push(byte as char);
-            self.advance(1);
+    if let Some((_, flag)) = program_only[1..].iter().find(|(set, _)| *set) {
+        if to_stdout {
+            return Err(format!("`{flag}` needs an output file"));
         }
+    }
 
-        if !number.is_empty() {
-            number.parse::<usize>().ok()
-        } else {
-            None
-        }
+    if command == Command::Check && parsed.output.is_some() {
+        return Err("`check` doesn't write any output".to_string());
     }
 
-    fn eof(&self) -> bool {
-        self.position >= self.bytes.len()
+    if command == Command::Fmt && parsed.output.is_some() {
+        return Err("`fmt` rewrites its inputs in place".to_string());
     }
-}
 
-#[derive(Debug)]
-pub enum Instruction {
-    Addr(AddrToken),
-    Comp(DestToken, CompToken, JumpToken),
-}
-impl Instruction {
-    fn to_binary(&self) -> Vec<u8> {
-        match self {
-            Instruction::Addr(addr) => match addr {
-                AddrToken::Static(addr) => format!("0{addr:015b}\n").into_bytes(),
-                AddrToken::Dynamic(_) => panic!("should not call to_binary on dynamic addr"),
-            },
-
-            Instruction::Comp(dest, comp, jump) => format!(
-                "111{:07b}{:03b}{:03b}\n",
-                comp.code(),
-                dest.code(),
-                jump.code()
-            )
-            .into_bytes(),
-        }
+    if parsed.check && command != Command::Fmt {
+        return Err("`--check` only applies to `fmt`".to_string());
     }
-}
 
-#[derive(Debug)]
-enum Token {
-    Dest(DestToken),
-    Comp(CompToken),
-    Jump(JumpToken),
-    Addr(AddrToken),
-    Label(String),
+    Ok(parsed)
 }
 
-#[derive(Debug)]
-pub enum DestToken {
-    A,
-    AD,
-    ADM,
-    AM,
-    D,
-    Empty,
-    M,
-    MD,
-}
-impl DestToken {
-    fn code(&self) -> usize {
-        match self {
-            Self::Empty => 0,
-            Self::M => 1,
-            Self::D => 2,
-            Self::MD => 3,
-            Self::A => 4,
-            Self::AM => 5,
-            Self::AD => 6,
-            Self::ADM => 7,
-        }
+// `NAME` or `NAME=value`, where the value is a constant expression
+fn parse_define(define: &str, defined: &[(String, usize)]) -> Result<(String, usize), String> {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    let mut chars = name.chars();
+
+    if !matches!(chars.next(), Some('A'..='Z' | 'a'..='z' | '_' | '$'))
+        || !chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+    {
+        return Err(format!("`-D {define}` doesn't start with a valid name"));
     }
-}
 
-#[derive(Debug)]
-pub enum CompToken {
-    Zero,
-    One,
-    A,
-    D,
-    M,
-    AddA1,
-    AddD1,
-    AddDA,
-    AddDM,
-    AddM1,
-    AndDA,
-    AndDM,
-    InvertA,
-    InvertD,
-    InvertM,
-    Negate1,
-    NegateA,
-    NegateD,
-    NegateM,
-    OrDA,
-    OrDM,
-    SubA1,
-    SubAD,
-    SubD1,
-    SubDA,
-    SubDM,
-    SubM1,
-    SubMD,
-}
-impl CompToken {
-    fn code(&self) -> usize {
-        match self {
-            Self::Zero => 0b0101010,
-            Self::One => 0b0111111,
-            Self::A => 0b0110000,
-            Self::D => 0b0001100,
-            Self::M => 0b1110000,
-            Self::AddA1 => 0b0110111,
-            Self::AddD1 => 0b0011111,
-            Self::AddDA => 0b0000010,
-            Self::AddDM => 0b1000010,
-            Self::AddM1 => 0b1110111,
-            Self::AndDA => 0b0000000,
-            Self::AndDM => 0b1000000,
-            Self::InvertA => 0b0110001,
-            Self::InvertD => 0b0001101,
-            Self::InvertM => 0b1110001,
-            Self::Negate1 => 0b0111010,
-            Self::NegateA => 0b0110011,
-            Self::NegateD => 0b0001111,
-            Self::NegateM => 0b1110011,
-            Self::OrDA => 0b0010101,
-            Self::OrDM => 0b1010101,
-            Self::SubA1 => 0b0110010,
-            Self::SubAD => 0b0000111,
-            Self::SubD1 => 0b0001110,
-            Self::SubDA => 0b0010011,
-            Self::SubDM => 0b1010011,
-            Self::SubM1 => 0b1110010,
-            Self::SubMD => 0b1000111,
-        }
+    if PREDEFINED_SYMBOLS.iter().any(|(symbol, _)| *symbol == name) {
+        return Err(format!(
+            "`{name}` is a predefined symbol, so it can't be defined with `-D`"
+        ));
     }
-}
 
-#[derive(Debug)]
-pub enum JumpToken {
-    JEQ,
-    JGE,
-    JGT,
-    JLE,
-    JLT,
-    JMP,
-    JNE,
-    Empty,
-}
-impl JumpToken {
-    fn code(&self) -> usize {
-        match self {
-            Self::Empty => 0,
-            Self::JGT => 1,
-            Self::JEQ => 2,
-            Self::JGE => 3,
-            Self::JLT => 4,
-            Self::JNE => 5,
-            Self::JLE => 6,
-            Self::JMP => 7,
-        }
+    if defined.iter().any(|(other, _)| other == name) {
+        return Err(format!("`{name}` is defined twice with `-D`"));
     }
-}
 
-#[derive(Debug)]
-pub enum AddrToken {
-    Static(usize),
-    Dynamic(String),
-}
+    let value = Expr::parse(value)
+        .map_err(|e| e.message)
+        .and_then(|expr| expr.eval(&mut |symbol| Err(format!("`{symbol}` is not a number"))))
+        .map_err(|message| format!("invalid value for `-D {name}`: {message}"))?;
 
-impl std::fmt::Debug for dyn LexerState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.name())
+    match usize::try_from(value) {
+        Ok(value) if value < ROM_SIZE => Ok((name.to_string(), value)),
+        _ => Err(format!(
+            "`-D {name}` is {value}, but values go from 0 to 32767"
+        )),
     }
 }
 
-trait LexerState {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>>;
-    fn name(&self) -> String {
-        std::any::type_name::<Self>().into()
-    }
-}
+// a single instruction, like `0` or `@0`
+fn parse_padding(text: &str) -> Result<Instruction, String> {
+    let invalid = || format!("`--pad {text}` isn't a single instruction, like `0` or `@0`");
+    let program = assembler::assemble(text).map_err(|_| invalid())?;
 
-struct LexInstructionStart;
-impl LexerState for LexInstructionStart {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_whitespace() => Some(Box::new(Self)),
-            _ if lexer.accept_str("//") => Some(Box::new(LexComment)),
-            _ if lexer.accept_str("@") => Some(Box::new(LexAddress)),
-            _ if lexer.accept_str("(") => Some(Box::new(LexLabel)),
-            _ if lexer.eof() => None,
-            _ => Some(Box::new(LexDest)),
+    match program.instructions.as_slice() {
+        [instruction] if program.labels.is_empty() && program.variables.is_empty() => {
+            Ok(instruction.clone())
         }
+        _ => Err(invalid()),
     }
 }
 
-struct LexComment;
-impl LexerState for LexComment {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_eol() => Some(Box::new(LexInstructionStart)),
-            _ if lexer.accept_any() => Some(Box::new(Self)),
-            _ => None,
-        }
+fn build(args: &Args) -> Result<ExitCode, Failure> {
+    if args.object {
+        return build_object(args);
     }
-}
 
-struct LexAddress;
-impl LexerState for LexAddress {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
+    let Some(program) = report(
+        args,
+        assembler::assemble_with(read_sources(args)?, &options(args)),
+    ) else {
+        return Ok(ExitCode::from(EXIT_ERRORS));
+    };
 
-        if let Some(label) = lexer.accept_label() {
-            lexer.emit(Token::Addr(AddrToken::Dynamic(label)));
-        } else if let Some(addr) = lexer.accept_addr() {
-            lexer.emit(Token::Addr(AddrToken::Static(addr)));
+    write_program(args, &program, "assembling")
+}
+
+fn build_object(args: &Args) -> Result<ExitCode, Failure> {
+    let object = match assembler::assemble_object(read_sources(args)?, &options(args)) {
+        Ok(object) => object,
+        Err(diagnostics) => {
+            report_errors(args, &diagnostics);
+            return Ok(ExitCode::from(EXIT_ERRORS));
         }
+    };
 
-        Some(Box::new(LexInstructionEnd))
+    let output = output_path(args, "hobj");
+
+    if !args.quiet {
+        eprintln!(
+            "assembling {} into {}",
+            describe(&args.inputs),
+            if output == "-" { "stdout" } else { &output }
+        );
     }
+
+    create(&output)?.write_all(object.to_string().as_bytes())?;
+
+    Ok(ExitCode::SUCCESS)
 }
 
-struct LexLabel;
-impl LexerState for LexLabel {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        if let Some(label) = lexer.accept_label() {
-            lexer.emit(Token::Label(label));
-            Some(Box::new(LexLabelEnd))
+fn link(args: &Args) -> Result<ExitCode, Failure> {
+    let mut objects = vec![];
+
+    for input in &args.inputs {
+        let text = if input == "-" {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
         } else {
-            panic!(
-                "invalid character in label at line {}, col {}",
-                lexer.row, lexer.col
-            );
-        }
+            std::fs::read_to_string(input).map_err(|e| format!("couldn't read {input}: {e}"))?
+        };
+
+        let object: assembler::Object = text.parse().map_err(|e| format!("{input}: {e}"))?;
+        objects.push(object);
     }
+
+    let Some(program) = report(args, assembler::link(&objects)) else {
+        return Ok(ExitCode::from(EXIT_ERRORS));
+    };
+
+    write_program(args, &program, "linking")
 }
 
-struct LexLabelEnd;
-impl LexerState for LexLabelEnd {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_str(")") => Some(Box::new(LexInstructionEnd)),
-            _ => panic!(
-                "invalid character in label at line {}, col {}",
-                lexer.row, lexer.col
-            ),
-        }
+// writes the program built from the inputs, along with the listing, symbol file and source map
+// if they were asked for
+fn write_program(args: &Args, program: &Program, verb: &str) -> Result<ExitCode, Failure> {
+    let output = output_path(args, args.format.extension());
+
+    if !args.quiet {
+        eprintln!(
+            "{verb} {} into {}",
+            describe(&args.inputs),
+            if output == "-" { "stdout" } else { &output }
+        );
     }
-}
 
-struct LexDest;
-impl LexerState for LexDest {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-
-        match () {
-            _ if lexer.accept_str("A=") => lexer.emit(Token::Dest(DestToken::A)),
-            _ if lexer.accept_str("AD=") => lexer.emit(Token::Dest(DestToken::AD)),
-            _ if lexer.accept_str("ADM=") => lexer.emit(Token::Dest(DestToken::ADM)),
-            _ if lexer.accept_str("AM=") => lexer.emit(Token::Dest(DestToken::AM)),
-            _ if lexer.accept_str("D=") => lexer.emit(Token::Dest(DestToken::D)),
-            _ if lexer.accept_str("MD=") => lexer.emit(Token::Dest(DestToken::MD)),
-            _ if lexer.accept_str("M=") => lexer.emit(Token::Dest(DestToken::M)),
-            _ => lexer.emit(Token::Dest(DestToken::Empty)),
-        };
+    Generator::new(create(&output)?, program).write(args.format)?;
 
-        Some(Box::new(LexComp))
+    if args.optimize && !args.quiet {
+        eprintln!(
+            "optimized away {} word{}",
+            program.words_saved,
+            if program.words_saved == 1 { "" } else { "s" }
+        );
     }
-}
 
-struct LexComp;
-impl LexerState for LexComp {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_str("A+1") => lexer.emit(Token::Comp(CompToken::AddA1)),
-            _ if lexer.accept_str("D+1") => lexer.emit(Token::Comp(CompToken::AddD1)),
-            _ if lexer.accept_str("D+A") => lexer.emit(Token::Comp(CompToken::AddDA)),
-            _ if lexer.accept_str("D+M") => lexer.emit(Token::Comp(CompToken::AddDM)),
-            _ if lexer.accept_str("M+1") => lexer.emit(Token::Comp(CompToken::AddM1)),
-            _ if lexer.accept_str("D+A") => lexer.emit(Token::Comp(CompToken::AddDM)),
-            _ if lexer.accept_str("A-1") => lexer.emit(Token::Comp(CompToken::SubA1)),
-            _ if lexer.accept_str("A-D") => lexer.emit(Token::Comp(CompToken::SubAD)),
-            _ if lexer.accept_str("D-1") => lexer.emit(Token::Comp(CompToken::SubD1)),
-            _ if lexer.accept_str("D-A") => lexer.emit(Token::Comp(CompToken::SubDA)),
-            _ if lexer.accept_str("D-M") => lexer.emit(Token::Comp(CompToken::SubDM)),
-            _ if lexer.accept_str("M-1") => lexer.emit(Token::Comp(CompToken::SubM1)),
-            _ if lexer.accept_str("M-D") => lexer.emit(Token::Comp(CompToken::SubMD)),
-            _ if lexer.accept_str("D&A") => lexer.emit(Token::Comp(CompToken::AndDA)),
-            _ if lexer.accept_str("D&M") => lexer.emit(Token::Comp(CompToken::AndDM)),
-            _ if lexer.accept_str("D|A") => lexer.emit(Token::Comp(CompToken::OrDA)),
-            _ if lexer.accept_str("D|M") => lexer.emit(Token::Comp(CompToken::OrDM)),
-            _ if lexer.accept_str("!A") => lexer.emit(Token::Comp(CompToken::InvertA)),
-            _ if lexer.accept_str("!D") => lexer.emit(Token::Comp(CompToken::InvertD)),
-            _ if lexer.accept_str("!M") => lexer.emit(Token::Comp(CompToken::InvertM)),
-            _ if lexer.accept_str("-1") => lexer.emit(Token::Comp(CompToken::Negate1)),
-            _ if lexer.accept_str("-A") => lexer.emit(Token::Comp(CompToken::NegateA)),
-            _ if lexer.accept_str("-D") => lexer.emit(Token::Comp(CompToken::NegateD)),
-            _ if lexer.accept_str("-M") => lexer.emit(Token::Comp(CompToken::NegateM)),
-            _ if lexer.accept_str("0") => lexer.emit(Token::Comp(CompToken::Zero)),
-            _ if lexer.accept_str("1") => lexer.emit(Token::Comp(CompToken::One)),
-            _ if lexer.accept_str("A") => lexer.emit(Token::Comp(CompToken::A)),
-            _ if lexer.accept_str("D") => lexer.emit(Token::Comp(CompToken::D)),
-            _ if lexer.accept_str("M") => lexer.emit(Token::Comp(CompToken::M)),
-
-            _ => panic!(
-                "invalid computation starting at line {}, col {}",
-                lexer.row, lexer.col
-            ),
-        }
+    let output = Path::new(&output);
+
+    if args.listing {
+        Generator::new(create_file(&output.with_extension("lst"))?, program).write_listing()?;
+    }
 
-        Some(Box::new(LexAfterComp))
+    if args.symbols {
+        Generator::new(create_file(&output.with_extension("sym"))?, program).write_symbols()?;
     }
+
+    if args.source_map {
+        let map = create_file(&output.with_extension("map.json"))?;
+        Generator::new(map, program).write_source_map()?;
+    }
+
+    Ok(ExitCode::SUCCESS)
 }
 
-struct LexAfterComp;
-impl LexerState for LexAfterComp {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_str(";") => Some(Box::new(LexJump)),
-            _ => {
-                lexer.emit(Token::Jump(JumpToken::Empty));
-                Some(Box::new(LexInstructionEnd))
-            }
-        }
+// -o, or else the first input with its extension changed (or stdout, when it's stdin)
+fn output_path(args: &Args, extension: &str) -> String {
+    match &args.output {
+        Some(output) => output.clone(),
+        None if args.inputs[0] == "-" => "-".to_string(),
+        None => Path::new(&args.inputs[0])
+            .with_extension(extension)
+            .to_string_lossy()
+            .into_owned(),
     }
 }
 
-struct LexJump;
-impl LexerState for LexJump {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_str("JGT") => lexer.emit(Token::Jump(JumpToken::JGT)),
-            _ if lexer.accept_str("JEQ") => lexer.emit(Token::Jump(JumpToken::JEQ)),
-            _ if lexer.accept_str("JGE") => lexer.emit(Token::Jump(JumpToken::JGE)),
-            _ if lexer.accept_str("JLT") => lexer.emit(Token::Jump(JumpToken::JLT)),
-            _ if lexer.accept_str("JNE") => lexer.emit(Token::Jump(JumpToken::JNE)),
-            _ if lexer.accept_str("JLE") => lexer.emit(Token::Jump(JumpToken::JLE)),
-            _ if lexer.accept_str("JMP") => lexer.emit(Token::Jump(JumpToken::JMP)),
-            _ => panic!(
-                "invalid jump starting at line {}, col {}",
-                lexer.row, lexer.col
-            ),
+fn disasm(args: &Args) -> Result<ExitCode, Failure> {
+    let input = &args.inputs[0];
+    let bytes = if input == "-" {
+        let mut bytes = vec![];
+        std::io::stdin().read_to_end(&mut bytes)?;
+        bytes
+    } else {
+        std::fs::read(input).map_err(|e| format!("couldn't read {input}: {e}"))?
+    };
+
+    let words = disassembler::read_hack(&bytes).map_err(|e| format!("{input}: {e}"))?;
+    let disassembly = disassembler::disassemble(&words, args.isa);
+
+    create(args.output.as_deref().unwrap_or("-"))?.write_all(disassembly.source.as_bytes())?;
+
+    if !args.quiet {
+        for addr in &disassembly.unknown {
+            eprintln!(
+                "warning: {input}: ROM[{addr}] ({:016b}) is not a documented instruction",
+                words[*addr]
+            );
         }
 
-        Some(Box::new(LexInstructionEnd))
+        for addr in &disassembly.undocumented {
+            eprintln!(
+                "warning: {input}: ROM[{addr}] ({:016b}) has comp bits that aren't a documented \
+                 computation",
+                words[*addr]
+            );
+        }
     }
+
+    Ok(ExitCode::SUCCESS)
 }
 
-struct LexInstructionEnd;
-impl LexerState for LexInstructionEnd {
-    fn scan(&self, lexer: &mut Lexer) -> Option<Box<dyn LexerState>> {
-        // println!("in {}", self.name());
-        match () {
-            _ if lexer.accept_str("//") => Some(Box::new(LexComment)),
-            _ if lexer.accept_non_eol_whitespace() => Some(Box::new(Self)),
-            _ if lexer.accept_eol() => Some(Box::new(LexInstructionStart)),
-            _ if lexer.eof() => None,
-            _ => panic!(
-                "unexpected character after instruction: {:?} at line {}, col {}",
-                lexer.current_char(),
-                lexer.row,
-                lexer.col
-            ),
-        }
+fn check(args: &Args) -> Result<ExitCode, Failure> {
+    match assemble(args)? {
+        Some(_) => Ok(ExitCode::SUCCESS),
+        None => Ok(ExitCode::from(EXIT_ERRORS)),
     }
 }
 
-mod parser {
-    use super::*;
+// rewrites each input in the canonical style, as long as it still assembles to the same program.
+// with --check, nothing is written and the inputs that would change are listed instead.
+fn fmt(args: &Args) -> Result<ExitCode, Failure> {
+    let mut failed = false;
 
-    pub struct Parser {
-        lexer: Lexer,
-        symbols: HashMap<String, usize>,
-        instructions: Vec<Instruction>,
-    }
-    impl Parser {
-        pub fn from_str(s: &str) -> Self {
-            Self {
-                lexer: Lexer::from_str(s),
-                symbols: HashMap::new(),
-                instructions: vec![],
-            }
-            .add_default_symbols()
-            .first_pass()
-            .second_pass()
-        }
+    for input in &args.inputs {
+        let source = if input == "-" {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        } else {
+            std::fs::read_to_string(input).map_err(|e| format!("couldn't read {input}: {e}"))?
+        };
 
-        fn add_default_symbols(mut self) -> Self {
-            self.symbols.insert("SP".to_string(), 0);
-            self.symbols.insert("LCL".to_string(), 1);
-            self.symbols.insert("ARG".to_string(), 2);
-            self.symbols.insert("THIS".to_string(), 3);
-            self.symbols.insert("THAT".to_string(), 4);
-            self.symbols.insert("R0".to_string(), 0);
-            self.symbols.insert("R1".to_string(), 1);
-            self.symbols.insert("R2".to_string(), 2);
-            self.symbols.insert("R3".to_string(), 3);
-            self.symbols.insert("R4".to_string(), 4);
-            self.symbols.insert("R5".to_string(), 5);
-            self.symbols.insert("R6".to_string(), 6);
-            self.symbols.insert("R7".to_string(), 7);
-            self.symbols.insert("R8".to_string(), 8);
-            self.symbols.insert("R9".to_string(), 9);
-            self.symbols.insert("R10".to_string(), 10);
-            self.symbols.insert("R11".to_string(), 11);
-            self.symbols.insert("R12".to_string(), 12);
-            self.symbols.insert("R13".to_string(), 13);
-            self.symbols.insert("R14".to_string(), 14);
-            self.symbols.insert("R15".to_string(), 15);
-            self.symbols.insert("SCREEN".to_string(), 16384);
-            self.symbols.insert("KBD".to_string(), 24576);
-            self
-        }
+        let formatted = formatter::format(&source);
+        let name = describe(std::slice::from_ref(input));
 
-        fn first_pass(mut self) -> Self {
-            loop {
-                match self.lexer.next_token() {
-                    Some(Token::Label(label)) => {
-                        if self.symbols.get(&label).is_some() {
-                            panic!("the ({label}) label is defined twice");
-                        } else {
-                            self.symbols.insert(label, self.instructions.len());
-                        }
-                    }
-
-                    Some(Token::Addr(token @ AddrToken::Dynamic(_))) => {
-                        self.instructions.push(Instruction::Addr(token));
-                    }
-
-                    Some(Token::Addr(addr)) => self.instructions.push(Instruction::Addr(addr)),
-
-                    Some(Token::Dest(dest)) => {
-                        let comp = self.lexer.next_token();
-                        let jump = self.lexer.next_token();
-
-                        if let Some(Token::Comp(comp)) = comp {
-                            if let Some(Token::Jump(jump)) = jump {
-                                self.instructions.push(Instruction::Comp(dest, comp, jump));
-                            } else {
-                                panic!("expected jump token after {comp:?} but got {jump:?}");
-                            }
-                        } else {
-                            panic!("expected comp token after {dest:?} but got {comp:?}");
-                        }
-                    }
-                    Some(token) => {
-                        panic!("unexpected token {token:?}");
-                    }
-                    None => break,
-                }
+        if !same_program(args, input, &source, &formatted) {
+            eprintln!("error: formatting {name} would change the program it assembles to");
+            failed = true;
+        } else if args.check {
+            if formatted != source {
+                println!("{name} isn't formatted");
+                failed = true;
             }
+        } else if input == "-" {
+            std::io::stdout().write_all(formatted.as_bytes())?;
+        } else if formatted != source {
+            std::fs::write(input, formatted).map_err(|e| format!("couldn't write {input}: {e}"))?;
 
-            self
+            if !args.quiet {
+                eprintln!("formatted {input}");
+            }
         }
+    }
 
-        fn second_pass(mut self) -> Self {
-            let mut next_dynamic_address = 15;
-
-            self.instructions = self
-                .instructions
-                .into_iter()
-                .map(|instruction| match instruction {
-                    Instruction::Addr(AddrToken::Dynamic(label)) => {
-                        if let Some(&value) = self.symbols.get(&label) {
-                            Instruction::Addr(AddrToken::Static(value))
-                        } else {
-                            next_dynamic_address += 1;
-                            self.symbols.insert(label, next_dynamic_address);
-                            Instruction::Addr(AddrToken::Static(next_dynamic_address))
-                        }
-                    }
-                    _ => instruction,
-                })
-                .collect();
-
-            self
-        }
+    match failed {
+        true => Ok(ExitCode::from(EXIT_ERRORS)),
+        false => Ok(ExitCode::SUCCESS),
     }
+}
 
-    impl IntoIterator for Parser {
-        type Item = Instruction;
-        type IntoIter = std::vec::IntoIter<Self::Item>;
-        fn into_iter(self) -> Self::IntoIter {
-            self.instructions.into_iter()
-        }
+// whether the formatted text of `input` assembles to the same words as the original. a file that
+// doesn't assemble on its own has nothing to compare.
+fn same_program(args: &Args, input: &str, source: &str, formatted: &str) -> bool {
+    let assemble = |text: &str| {
+        let mut sources = Sources::new();
+
+        match input {
+            "-" => sources.add("<stdin>", text),
+            path => sources.add_at(Path::new(path), text),
+        };
+
+        assembler::assemble_with(sources, &options(args)).map(|program| program.words)
+    };
+
+    match assemble(source) {
+        Ok(words) => assemble(formatted).is_ok_and(|formatted| formatted == words),
+        Err(_) => true,
     }
 }
 
-use parser::Parser;
+fn cfg(args: &Args) -> Result<ExitCode, Failure> {
+    let Some(program) = assemble(args)? else {
+        return Ok(ExitCode::from(EXIT_ERRORS));
+    };
 
-struct Generator<'a> {
-    dest: Box<dyn std::io::Write>,
-    source: &'a str,
+    let output = args.output.as_deref().unwrap_or("-");
+    Generator::new(create(output)?, &program).write_graph(args.graph)?;
+
+    Ok(ExitCode::SUCCESS)
 }
-impl<'a> Generator<'a> {
-    fn from_str(dest: Box<dyn std::io::Write>, source: &'a str) -> Generator<'a> {
-        Self { dest, source }
-    }
 
-    fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let instructions = Parser::from_str(self.source).into_iter();
+fn symbols(args: &Args) -> Result<ExitCode, Failure> {
+    let Some(program) = assemble(args)? else {
+        return Ok(ExitCode::from(EXIT_ERRORS));
+    };
+
+    let output = args.output.as_deref().unwrap_or("-");
+    Generator::new(create(output)?, &program).write_symbols()?;
+
+    Ok(ExitCode::SUCCESS)
+}
 
-        for instruction in instructions {
-            self.dest.write_all(instruction.to_binary().as_slice())?;
+// assembles the inputs, printing any diagnostics. `None` means the program had errors.
+fn assemble(args: &Args) -> Result<Option<Program>, Failure> {
+    let sources = read_sources(args)?;
+    Ok(report(
+        args,
+        assembler::assemble_with(sources, &options(args)),
+    ))
+}
+
+fn read_sources(args: &Args) -> Result<Sources, Failure> {
+    let mut sources = Sources::new();
+
+    for input in &args.inputs {
+        if input == "-" {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            sources.add("<stdin>", text);
+        } else {
+            sources
+                .load(Path::new(input))
+                .map_err(|e| format!("couldn't read {input}: {e}"))?;
         }
+    }
+
+    Ok(sources)
+}
 
-        Ok(())
+fn options(args: &Args) -> Options {
+    Options {
+        optimize: args.optimize,
+        defines: args.defines.clone(),
+        padding: args.padding.clone(),
+        isa: args.isa,
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
+// prints the warnings for a program, or the errors that stopped it from being built
+fn report(args: &Args, result: Result<Program, Diagnostics>) -> Option<Program> {
+    match result {
+        Ok(program) => {
+            if !args.quiet {
+                for warning in &program.warnings {
+                    eprintln!("{}\n", warning.render(&program.sources));
+                }
+            }
 
-    if args.len() != 2 {
-        panic!("usage: {} <source>.asm", args[0])
-    }
+            Some(program)
+        }
 
-    let src_path = std::path::Path::new(&args[1]);
-    let stem = src_path.file_stem().unwrap();
-    let parent = src_path.parent().unwrap();
-    let dst_path = parent.join(format!("{}.hack", stem.to_str().unwrap()));
-    let src = std::fs::read_to_string(src_path)?;
-    let dst = std::fs::File::create(dst_path.clone())?;
+        Err(diagnostics) => {
+            report_errors(args, &diagnostics);
+            None
+        }
+    }
+}
 
-    let mut generator = Generator::from_str(Box::new(dst), src.as_str());
+fn report_errors(args: &Args, diagnostics: &Diagnostics) {
+    let verb = match args.command {
+        Command::Link => "link",
+        _ => "assemble",
+    };
 
+    eprintln!("{}", diagnostics.render());
     eprintln!(
-        "assembling {} into {}",
-        src_path.to_string_lossy(),
-        dst_path.to_string_lossy()
+        "error: could not {verb} {} due to {} previous error{}",
+        describe(&args.inputs),
+        diagnostics.len(),
+        if diagnostics.len() == 1 { "" } else { "s" }
     );
+}
+
+// the inputs as they're named in messages
+fn describe(inputs: &[String]) -> String {
+    let names: Vec<_> = inputs
+        .iter()
+        .map(|input| if input == "-" { "stdin" } else { input })
+        .collect();
+
+    names.join(", ")
+}
+
+// opens `path` for writing, where `-` is stdout
+fn create(path: &str) -> Result<Box<dyn Write>, Failure> {
+    if path == "-" {
+        Ok(Box::new(std::io::stdout().lock()))
+    } else {
+        create_file(Path::new(path))
+    }
+}
 
-    generator.run()?;
+fn create_file(path: &Path) -> Result<Box<dyn Write>, Failure> {
+    let file = std::fs::File::create(path)
+        .map_err(|e| format!("couldn't write {}: {e}", path.to_string_lossy()))?;
 
-    Ok(())
+    Ok(Box::new(std::io::BufWriter::new(file)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use assembler::AddrToken;
+
+    fn parse(args: &str) -> Result<Args, String> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        parse_args(&args)
+    }
 
     #[test]
-    fn simplest_instruction() -> Result<(), String> {
-        let mut lex = Lexer::from_str("0");
-        let dest = lex.next_token();
-        let comp = lex.next_token();
-        let jump = lex.next_token();
-        let eof = lex.next_token();
-        let actual = (dest, comp, jump, eof);
-
-        match actual {
-            (Some(Token::Dest(DestToken::Empty)), Some(Token::Comp(CompToken::Zero)), Some(Token::Jump(JumpToken::Empty)), None) => Ok(()),
-            _ => Err(format!("expected (Some(Dest(Empty)), Some(Comp(Zero)), Some(Jump(Empty)), None) but got {:?}", actual)),
-        }
+    fn parses_commands_and_options() {
+        let args = parse("build -O a.asm --format=bin -o out.bin - b.asm").unwrap();
+
+        assert_eq!(args.command, Command::Build);
+        assert_eq!(args.inputs, ["a.asm", "-", "b.asm"]);
+        assert_eq!(args.output.as_deref(), Some("out.bin"));
+        assert_eq!(args.format, Format::Binary);
+        assert_eq!(
+            parse("cfg --format json a.asm").unwrap().graph,
+            GraphFormat::Json
+        );
+        assert!(args.optimize && !args.quiet);
+
+        assert_eq!(parse("check -q -- -odd.asm").unwrap().inputs, ["-odd.asm"]);
+        assert_eq!(
+            parse("build -D DEBUG -DLEVEL=2*3 a.asm").unwrap().defines,
+            [("DEBUG".to_string(), 1), ("LEVEL".to_string(), 6)]
+        );
+        assert_eq!(
+            parse("check --pad=@0 a.asm").unwrap().padding,
+            Some(Instruction::Addr(AddrToken::Static(0)))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_command_lines() {
+        assert_eq!(parse("").unwrap_err(), "no command given");
+        assert_eq!(parse("make a.asm").unwrap_err(), "unknown command `make`");
+        assert_eq!(parse("build").unwrap_err(), "no input files");
+        assert_eq!(parse("build a.asm -o").unwrap_err(), "`-o` needs a value");
+        assert_eq!(
+            parse("check --listing a.asm").unwrap_err(),
+            "`--listing` only applies to `build` and `link`"
+        );
+        assert_eq!(
+            parse("build -D SP=3 a.asm").unwrap_err(),
+            "`SP` is a predefined symbol, so it can't be defined with `-D`"
+        );
+        assert_eq!(
+            parse("build --check a.asm").unwrap_err(),
+            "`--check` only applies to `fmt`"
+        );
+        assert_eq!(
+            parse("disasm a.hack b.hack").unwrap_err(),
+            "`disasm` takes a single ROM image"
+        );
     }
 }
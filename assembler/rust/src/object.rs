@@ -0,0 +1,303 @@
+// relocatable objects: code that's been through the first pass but not the second, so it can be
+// linked into a program along with other objects. objects are plain text, one record per line:
+//
+//     hackobj 1
+//     file Math.asm                      the files it was assembled from, numbered from 0
+//     label MULT 0 0:3:1-5               a label, its offset from the start of the object and span
+//     equ ROW 0:1:1-12 32                a constant and its expression
+//     word 0:4:1-4 1110110000010000      an instruction that doesn't need anything from the linker
+//     reloc 0:5:1-6 12                   `@label` for a label in this object, by offset
+//     extern 0:6:1-5 DIV                 `@name` for a label in another object, or a variable
+//     expr 0:7:1-10 SCREEN+ROW*5         `@expr`, evaluated once the objects are linked
+//
+// spans are `file:line:start-end`. every record after the header and files is either a label
+// or constant definition, or one word of code, in ROM order.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::diagnostic::Span;
+use crate::expr::Expr;
+use crate::parser::{Parser, PREDEFINED_SYMBOLS};
+use crate::tokens::{AddrToken, Instruction};
+
+const HEADER: &str = "hackobj 1";
+
+/// A relocatable object, which [`link`](crate::link) combines with others into a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+    /// The files the object was assembled from, by the names diagnostics use for them. Spans
+    /// refer to files by their index in this list.
+    pub files: Vec<String>,
+    /// Every label, with its offset from the start of the object, in ROM order.
+    pub labels: Vec<(String, usize, Span)>,
+    /// The `.equ` constants in the order they were defined.
+    pub constants: Vec<(String, Expr, Span)>,
+    /// Every word of code in ROM order, with where it came from.
+    pub code: Vec<(Site, Span)>,
+}
+
+/// One word of an object's code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Site {
+    /// An instruction that's already been encoded.
+    Word(u16),
+    /// An `@label` for a label in this object: the label's offset from the start of the object,
+    /// which the linker moves to wherever the object ends up in ROM.
+    Relocation(usize),
+    /// An `@name` for a symbol that isn't defined in this object: a label in another one, or a
+    /// variable if none of them define it.
+    External(String),
+    /// An `@` expression or constant, evaluated once every object has been laid out.
+    Expr(Expr),
+}
+
+impl Object {
+    /// Builds an object from a parser that's finished its first pass.
+    pub(crate) fn from_parser(parser: &Parser) -> Self {
+        let sources = &parser.sources;
+        let files = sources.iter().map(|(id, _)| sources.name(id).to_string());
+
+        let mut labels: Vec<_> = parser
+            .labels
+            .iter()
+            .map(|(name, span)| (name.clone(), parser.symbols[name], *span))
+            .collect();
+        labels.sort_by_key(|(_, offset, span)| (*offset, *span));
+
+        let constants = parser
+            .constants
+            .iter()
+            .map(|name| {
+                let (expr, span) = &parser.definitions[name];
+                (name.clone(), fold_defines(parser, expr), *span)
+            })
+            .collect();
+
+        let code = parser
+            .instructions
+            .iter()
+            .zip(&parser.spans)
+            .map(|(instruction, span)| (site(parser, instruction), *span))
+            .collect();
+
+        Self {
+            files: files.collect(),
+            labels,
+            constants,
+            code,
+        }
+    }
+}
+
+fn site(parser: &Parser, instruction: &Instruction) -> Site {
+    let Instruction::Addr(addr) = instruction else {
+        return Site::Word(instruction.encode());
+    };
+
+    match addr {
+        AddrToken::Static(value) => Site::Word(*value as u16),
+        AddrToken::Dynamic(name) if parser.labels.contains_key(name) => {
+            Site::Relocation(parser.symbols[name])
+        }
+        // predefined symbols and defines are the only other symbols the first pass knows
+        AddrToken::Dynamic(name) => match parser.symbols.get(name) {
+            Some(value) => Site::Word(*value as u16),
+            None if parser.definitions.contains_key(name) => Site::Expr(Expr::Symbol(name.clone())),
+            None => Site::External(name.clone()),
+        },
+        AddrToken::Expr(expr) => Site::Expr(fold_defines(parser, expr)),
+    }
+}
+
+// the linker doesn't know about `-D` defines, so their values are written into the expressions
+// that use them
+fn fold_defines(parser: &Parser, expr: &Expr) -> Expr {
+    match expr {
+        Expr::Symbol(name)
+            if !parser.labels.contains_key(name)
+                && !PREDEFINED_SYMBOLS.iter().any(|(symbol, _)| symbol == name) =>
+        {
+            match parser.symbols.get(name) {
+                Some(value) => Expr::Number(*value as i64),
+                None => expr.clone(),
+            }
+        }
+        Expr::Neg(operand) => Expr::Neg(Box::new(fold_defines(parser, operand))),
+        Expr::Binary(op, lhs, rhs) => Expr::Binary(
+            *op,
+            Box::new(fold_defines(parser, lhs)),
+            Box::new(fold_defines(parser, rhs)),
+        ),
+        _ => expr.clone(),
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+
+        for file in &self.files {
+            writeln!(f, "file {file}")?;
+        }
+
+        for (name, expr, span) in &self.constants {
+            writeln!(f, "equ {name} {} {expr}", SpanRecord(*span))?;
+        }
+
+        let mut labels = self.labels.iter().peekable();
+
+        for (offset, (site, span)) in self.code.iter().enumerate() {
+            while let Some((name, _, span)) = labels.next_if(|(_, at, _)| *at == offset) {
+                writeln!(f, "label {name} {offset} {}", SpanRecord(*span))?;
+            }
+
+            let span = SpanRecord(*span);
+
+            match site {
+                Site::Word(word) => writeln!(f, "word {span} {word:016b}")?,
+                Site::Relocation(offset) => writeln!(f, "reloc {span} {offset}")?,
+                Site::External(name) => writeln!(f, "extern {span} {name}")?,
+                Site::Expr(expr) => writeln!(f, "expr {span} {expr}")?,
+            }
+        }
+
+        // labels at the very end of the object
+        for (name, offset, span) in labels {
+            writeln!(f, "label {name} {offset} {}", SpanRecord(*span))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Object {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate().map(|(n, line)| (n + 1, line));
+
+        match lines.next() {
+            Some((_, HEADER)) => (),
+            _ => return Err(format!("not a Hack object: expected `{HEADER}` on line 1")),
+        }
+
+        let mut object = Object {
+            files: vec![],
+            labels: vec![],
+            constants: vec![],
+            code: vec![],
+        };
+
+        for (n, line) in lines {
+            let fail = |what: &str| format!("line {n}: {what}");
+            let span = |text: &str| parse_span(text).ok_or_else(|| fail("invalid span"));
+            let expr = |text: &str| Expr::parse(text).map_err(|_| fail("invalid expression"));
+            let offset = |text: &str| text.parse().map_err(|_| fail("invalid offset"));
+
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let fields: Vec<_> = match kind {
+                "label" | "equ" => rest.splitn(3, ' ').collect(),
+                _ => rest.splitn(2, ' ').collect(),
+            };
+
+            match (kind, fields.as_slice()) {
+                ("file", _) => object.files.push(rest.to_string()),
+
+                ("label", [name, at, at_span]) => {
+                    object
+                        .labels
+                        .push((name.to_string(), offset(at)?, span(at_span)?));
+                }
+
+                ("equ", [name, at_span, value]) => {
+                    object
+                        .constants
+                        .push((name.to_string(), expr(value)?, span(at_span)?));
+                }
+
+                ("word", [at_span, value]) => {
+                    let word = u16::from_str_radix(value, 2).map_err(|_| fail("invalid word"))?;
+                    object.code.push((Site::Word(word), span(at_span)?));
+                }
+
+                ("reloc", [at_span, value]) => {
+                    object
+                        .code
+                        .push((Site::Relocation(offset(value)?), span(at_span)?));
+                }
+
+                ("extern", [at_span, name]) => {
+                    object
+                        .code
+                        .push((Site::External(name.to_string()), span(at_span)?));
+                }
+
+                ("expr", [at_span, value]) => {
+                    object.code.push((Site::Expr(expr(value)?), span(at_span)?));
+                }
+
+                ("", _) => (),
+
+                ("label" | "equ" | "word" | "reloc" | "extern" | "expr", _) => {
+                    return Err(fail(&format!("incomplete `{kind}` record")));
+                }
+
+                _ => return Err(fail(&format!("unknown record `{kind}`"))),
+            }
+        }
+
+        Ok(object)
+    }
+}
+
+struct SpanRecord(Span);
+
+impl fmt::Display for SpanRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Span {
+            file,
+            line,
+            start,
+            end,
+        } = self.0;
+
+        write!(f, "{file}:{line}:{start}-{end}")
+    }
+}
+
+fn parse_span(text: &str) -> Option<Span> {
+    let (file, rest) = text.split_once(':')?;
+    let (line, rest) = rest.split_once(':')?;
+    let (start, end) = rest.split_once('-')?;
+
+    Some(
+        Span::new(line.parse().ok()?, start.parse().ok()?, end.parse().ok()?)
+            .in_file(file.parse().ok()?),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble_object, Options, Sources};
+
+    #[test]
+    fn records_relocations_and_external_references() {
+        let source = ".equ ROW 32\n(MULT)\n@MULT\n0;JMP\n@DIV\n@SCREEN+ROW\n@R1\n";
+        let object = assemble_object(Sources::from(source), &Options::default()).unwrap();
+        let sites: Vec<_> = object.code.iter().map(|(site, _)| site.clone()).collect();
+
+        assert_eq!(
+            sites,
+            [
+                Site::Relocation(0),
+                Site::Word(0xea87),
+                Site::External("DIV".to_string()),
+                Site::Expr(Expr::parse("SCREEN+ROW").unwrap()),
+                Site::Word(1),
+            ]
+        );
+        assert_eq!(object.to_string().parse::<Object>().unwrap(), object);
+    }
+}
@@ -0,0 +1,223 @@
+// a peephole optimizer that runs between the first and second passes, while A-instructions
+// still refer to labels by name. it removes:
+//
+//   - A-instructions that load the value A already holds, e.g. the second `@SP` in
+//     `@SP, M=M+1, @SP, A=M`
+//   - A-instructions whose value is overwritten by the next one before it's used
+//   - `M=D` right after `D=M` (and the other way around), since the registers already match
+//   - jumps to the very next instruction, along with the A-instruction that loaded the target
+//
+// a label is an entry point that can be reached with any value in A, so nothing is assumed
+// about A across one. removing an instruction moves the labels on it to the next one, and
+// labels after it back by one. programs that find ROM addresses any other way, like jumping to
+// a numeric address or doing arithmetic with a label, are left alone, and so are programs that
+// lay out ROM themselves with `.org`, `.fill` or `.align`.
+
+use std::collections::HashSet;
+
+use crate::parser::Parser;
+use crate::tokens::{AddrToken, CompToken, DestToken, Instruction, JumpToken};
+
+/// Optimizes the parser's instructions in place, returning the number of words saved.
+pub(crate) fn run(parser: &mut Parser) -> usize {
+    if !relocatable(parser) {
+        return 0;
+    }
+
+    let before = parser.instructions.len();
+
+    loop {
+        let removed = sweep(parser);
+
+        if removed.is_empty() {
+            break;
+        }
+
+        remove(parser, &removed);
+    }
+
+    before - parser.instructions.len()
+}
+
+// whether every ROM address in the program comes from a label, so instructions can move
+fn relocatable(parser: &Parser) -> bool {
+    let numeric_jump = parser.instructions.windows(2).any(|pair| {
+        matches!(
+            pair,
+            [Instruction::Addr(AddrToken::Static(_)), Instruction::Comp(_, _, jump)]
+                if *jump != JumpToken::Empty
+        )
+    });
+
+    let label_arithmetic = parser
+        .instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Addr(AddrToken::Expr(expr)) => Some(expr),
+            _ => None,
+        })
+        .chain(parser.definitions.values().map(|(expr, _)| expr))
+        .flat_map(|expr| expr.symbols())
+        .any(|name| parser.labels.contains_key(name));
+
+    !numeric_jump && !label_arithmetic && parser.layout.is_empty()
+}
+
+// the indices of every instruction that can be removed, in order
+fn sweep(parser: &Parser) -> Vec<usize> {
+    let instructions = &parser.instructions;
+    let targets: HashSet<usize> = parser
+        .labels
+        .keys()
+        .map(|label| parser.symbols[label])
+        .collect();
+
+    let mut removed = vec![];
+    // what A is known to hold, and the last instruction that was kept since entering the block
+    let mut a: Option<&AddrToken> = None;
+    let mut previous: Option<&Instruction> = None;
+    // set when a removed instruction was an entry point, which makes the next one one instead
+    let mut entry = false;
+
+    let mut i = 0;
+
+    while i < instructions.len() {
+        if targets.contains(&i) || entry {
+            a = None;
+            previous = None;
+        }
+
+        entry = false;
+        let next = instructions.get(i + 1);
+
+        match &instructions[i] {
+            Instruction::Addr(addr) => {
+                let jump_to_next = jumps_to(parser, addr, i + 2)
+                    && !targets.contains(&(i + 1))
+                    && matches!(next, Some(Instruction::Comp(DestToken::Empty, _, _)));
+
+                if jump_to_next {
+                    removed.extend([i, i + 1]);
+                    entry = targets.contains(&i);
+                    i += 2;
+                    continue;
+                }
+
+                if a == Some(addr) || matches!(next, Some(Instruction::Addr(_))) {
+                    removed.push(i);
+                    entry = targets.contains(&i);
+                } else {
+                    a = Some(addr);
+                    previous = Some(&instructions[i]);
+                }
+            }
+
+            instruction @ Instruction::Comp(dest, _, _) => {
+                use {CompToken as C, DestToken as D, JumpToken::Empty};
+
+                let redundant = matches!(
+                    (previous, instruction),
+                    (
+                        Some(Instruction::Comp(D::D, C::M, Empty)),
+                        Instruction::Comp(D::M, C::D, Empty)
+                    ) | (
+                        Some(Instruction::Comp(D::M, C::D, Empty)),
+                        Instruction::Comp(D::D, C::M, Empty)
+                    )
+                );
+
+                if redundant {
+                    removed.push(i);
+                    entry = targets.contains(&i);
+                } else {
+                    if dest.code() & 0b100 != 0 {
+                        a = None;
+                    }
+
+                    previous = Some(instruction);
+                }
+            }
+        }
+
+        i += 1;
+    }
+
+    removed
+}
+
+// whether `addr` is a label on the instruction at `target`, and control can reach that
+// instruction with any value in A because it loads A itself (or the program ends there)
+fn jumps_to(parser: &Parser, addr: &AddrToken, target: usize) -> bool {
+    let AddrToken::Dynamic(label) = addr else {
+        return false;
+    };
+
+    parser.labels.contains_key(label)
+        && parser.symbols[label] == target
+        && matches!(
+            parser.instructions.get(target),
+            None | Some(Instruction::Addr(_))
+        )
+}
+
+// removes the instructions at `removed`, which is in order, moving the labels to match
+fn remove(parser: &mut Parser, removed: &[usize]) {
+    for label in parser.labels.keys() {
+        let address = parser.symbols[label];
+        let before = removed.partition_point(|&i| i < address);
+
+        parser.symbols.insert(label.clone(), address - before);
+    }
+
+    let removed: HashSet<usize> = removed.iter().copied().collect();
+
+    let mut i = 0;
+    parser.instructions.retain(|_| {
+        i += 1;
+        !removed.contains(&(i - 1))
+    });
+
+    let mut i = 0;
+    parser.spans.retain(|_| {
+        i += 1;
+        !removed.contains(&(i - 1))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble_with, Options, Sources};
+
+    fn optimize(source: &str) -> (Vec<String>, usize) {
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let program = assemble_with(Sources::from(source), &options).unwrap();
+        let instructions = program.instructions.iter().map(|i| i.to_string()).collect();
+
+        (instructions, program.words_saved)
+    }
+
+    #[test]
+    fn removes_redundant_instructions() {
+        let (instructions, saved) = optimize("@SP\nM=M+1\n@SP\nA=M\nD=M\nM=D\n@1\n@2\nD=A\n");
+        assert_eq!(instructions, ["@0", "M=M+1", "A=M", "D=M", "@2", "D=A"]);
+        assert_eq!(saved, 3);
+
+        let (instructions, saved) = optimize("@NEXT\n0;JMP\n(NEXT)\n@NEXT\nD;JGT\n");
+        assert_eq!(instructions, ["@0", "D;JGT"]);
+        assert_eq!(saved, 2);
+    }
+
+    #[test]
+    fn keeps_what_labels_can_reach() {
+        let (instructions, saved) = optimize("@SP\nD=M\n(LOOP)\n@SP\nM=D\n@LOOP\n0;JMP\n");
+        assert_eq!(instructions, ["@0", "D=M", "@0", "M=D", "@2", "0;JMP"]);
+        assert_eq!(saved, 0);
+
+        // jumping to a numeric address depends on the layout staying put
+        let (_, saved) = optimize("@1\n@4\n0;JMP\n@5\n@6\n");
+        assert_eq!(saved, 0);
+    }
+}
@@ -0,0 +1,1118 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expr::{BinaryOp, Expr};
+use crate::lexer::Lexer;
+use crate::lint;
+use crate::optimizer;
+use crate::preprocessor::{Expansion, Preprocessor};
+use crate::source::Sources;
+use crate::tokens::{
+    AddrToken, CompToken, DestToken, Instruction, Isa, JumpToken, Layout, Operand, Token,
+};
+use crate::Options;
+
+/// The number of words in the Hack computer's instruction memory.
+pub const ROM_SIZE: usize = 32768;
+
+/// Where variables are allocated: from RAM[16] up to the start of the screen's memory map.
+pub const VARIABLES: std::ops::RangeInclusive<usize> = 16..=16383;
+
+const ADDRESS_RANGE: &str = "A-instructions can only load values from 0 to 32767";
+
+/// The symbols every Hack program starts with.
+pub const PREDEFINED_SYMBOLS: [(&str, usize); 23] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R0", 0),
+    ("R1", 1),
+    ("R2", 2),
+    ("R3", 3),
+    ("R4", 4),
+    ("R5", 5),
+    ("R6", 6),
+    ("R7", 7),
+    ("R8", 8),
+    ("R9", 9),
+    ("R10", 10),
+    ("R11", 11),
+    ("R12", 12),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+pub struct Parser {
+    pub(crate) sources: Sources,
+    expansion: Expansion,
+    pub(crate) symbols: HashMap<String, usize>,
+    pub(crate) labels: HashMap<String, Span>,
+    pub(crate) variables: Vec<String>,
+    pub(crate) constants: Vec<String>,
+    pub(crate) definitions: HashMap<String, (Expr, Span)>,
+    /// The symbols each instruction referred to before they were resolved, in ROM order.
+    pub(crate) references: Vec<Vec<String>>,
+    pub(crate) warnings: Vec<Diagnostic>,
+    pub(crate) instructions: Vec<Instruction>,
+    pub(crate) spans: Vec<Span>,
+    /// What `.org`, `.fill` and `.align` pad with.
+    padding: Instruction,
+    isa: Isa,
+    /// Every `.org`, `.fill` and `.align`, with the instructions it padded with.
+    pub(crate) layout: Vec<(Layout, Span, Range<usize>)>,
+    pub(crate) tables: Vec<Table>,
+    pub(crate) words_saved: usize,
+    pub(crate) diagnostics: Vec<Diagnostic>,
+}
+
+impl FromStr for Parser {
+    type Err = Vec<Diagnostic>;
+
+    fn from_str(s: &str) -> Result<Self, Vec<Diagnostic>> {
+        let parser = Self::from_sources(Sources::from(s));
+
+        if parser.diagnostics.is_empty() {
+            Ok(parser)
+        } else {
+            Err(parser.diagnostics)
+        }
+    }
+}
+
+impl Parser {
+    /// Parses every root file in `sources` as one program, reading included files as they're
+    /// encountered. Symbols are only resolved when there are no errors.
+    pub fn from_sources(sources: Sources) -> Self {
+        Self::with_options(sources, &Options::default())
+    }
+
+    /// Like [`Parser::from_sources`], with the behavior `options` asks for.
+    pub fn with_options(sources: Sources, options: &Options) -> Self {
+        let mut parser = Self::unresolved(sources, options);
+
+        if parser.diagnostics.is_empty() {
+            parser = parser.second_pass();
+        }
+
+        if parser.diagnostics.is_empty() {
+            parser.warnings = lint::run(&parser);
+        }
+
+        parser.finish()
+    }
+
+    /// Runs everything up to the second pass, leaving labels relative to the start of the
+    /// program and every other symbol unresolved, for building relocatable objects.
+    pub(crate) fn unresolved(mut sources: Sources, options: &Options) -> Self {
+        let (expansion, diagnostics) = Preprocessor::run(&mut sources, &options.defines);
+
+        let padding = options.padding.clone().unwrap_or(Instruction::Comp(
+            DestToken::Empty,
+            CompToken::Zero,
+            JumpToken::Empty,
+        ));
+
+        let mut parser = Self {
+            expansion,
+            padding,
+            isa: options.isa,
+            diagnostics,
+            ..Self::empty(sources, &options.defines)
+        }
+        .first_pass();
+
+        if parser.diagnostics.is_empty() && options.optimize {
+            parser.words_saved = optimizer::run(&mut parser);
+        }
+
+        if parser.diagnostics.is_empty() {
+            parser.add_prologue();
+        }
+
+        parser
+    }
+
+    /// A parser with nothing in it but the predefined symbols and `defines`, which the linker
+    /// fills in.
+    pub(crate) fn empty(sources: Sources, defines: &[(String, usize)]) -> Self {
+        Self {
+            sources,
+            expansion: Expansion::default(),
+            symbols: HashMap::new(),
+            labels: HashMap::new(),
+            variables: vec![],
+            constants: vec![],
+            definitions: HashMap::new(),
+            references: vec![],
+            warnings: vec![],
+            instructions: vec![],
+            spans: vec![],
+            padding: Instruction::Comp(DestToken::Empty, CompToken::Zero, JumpToken::Empty),
+            layout: vec![],
+            tables: vec![],
+            isa: Isa::Standard,
+            words_saved: 0,
+            diagnostics: vec![],
+        }
+        .add_default_symbols(defines)
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Warnings from the lint pass, which only runs once the program has assembled.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Every file that went into the program, including the ones pulled in with `.include`.
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+
+    /// The instructions in ROM order. Every address is static once parsing has succeeded.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// The final symbol table: predefined symbols, labels and allocated variables.
+    pub fn symbols(&self) -> &HashMap<String, usize> {
+        &self.symbols
+    }
+
+    /// Where each instruction came from, in ROM order.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    fn add_default_symbols(mut self, defines: &[(String, usize)]) -> Self {
+        for (name, value) in PREDEFINED_SYMBOLS {
+            self.symbols.insert(name.to_string(), value);
+        }
+
+        for (name, value) in defines {
+            self.symbols.insert(name.clone(), *value);
+        }
+
+        self
+    }
+
+    fn first_pass(mut self) -> Self {
+        let preprocessed = self.diagnostics.len();
+        let mut scopes = Scopes::default();
+        // the lexer borrows the text, which is put back once it's done
+        let text = std::mem::take(&mut self.expansion.text);
+        let mut lexer = Lexer::new(&text);
+
+        loop {
+            match lexer.next() {
+                Some(Token::Label(label)) => {
+                    let span = lexer.span();
+                    // labels from macro expansions and control flow blocks don't start a new
+                    // scope for local labels
+                    let origin = self.expansion.origin(span.line);
+                    let expanded = !origin.calls.is_empty() || origin.generated;
+                    let label = scopes.define(label.to_string(), expanded);
+
+                    self.define_label(label, span);
+                }
+
+                Some(Token::NumericLabel(n)) => {
+                    let label = scopes.define_numeric(n);
+                    self.define_label(label, lexer.span());
+                }
+
+                Some(Token::Equ(name, mut expr)) => {
+                    let name = name.to_string();
+                    let span = lexer.span();
+
+                    for symbol in expr.symbols_mut() {
+                        self.resolve_local(&mut scopes, symbol, span);
+                    }
+
+                    if let Some(first) = self.first_definition(&name) {
+                        let first = self.expansion.origin(first.line).locate(first);
+                        let diagnostic =
+                            Diagnostic::error(format!("`{name}` is already defined"), span);
+
+                        self.diagnostics
+                            .push(diagnostic.with_note(first, "first defined here"));
+                    } else if self.symbols.contains_key(&name) {
+                        self.diagnostics.push(
+                            Diagnostic::error(format!("`{name}` is already defined"), span)
+                                .with_hint(builtin(&name)),
+                        );
+                    } else {
+                        self.constants.push(name.clone());
+                        self.definitions.insert(name, (expr, span));
+                    }
+                }
+
+                Some(Token::Addr(operand)) => {
+                    let mut addr = match operand {
+                        Operand::Number(value) => AddrToken::Static(value),
+                        Operand::Symbol(name) => AddrToken::Dynamic(name.to_string()),
+                        Operand::Expr(expr) => AddrToken::Expr(expr),
+                    };
+
+                    // include the @ in the span
+                    let span = lexer.span();
+                    let span = Span {
+                        start: span.start - 1,
+                        ..span
+                    };
+
+                    match &mut addr {
+                        AddrToken::Dynamic(name) => self.resolve_local(&mut scopes, name, span),
+                        AddrToken::Expr(expr) => {
+                            for symbol in expr.symbols_mut() {
+                                self.resolve_local(&mut scopes, symbol, span);
+                            }
+                        }
+                        AddrToken::Static(_) => (),
+                    }
+
+                    if let AddrToken::Static(value @ ROM_SIZE..) = addr {
+                        self.diagnostics.push(
+                            Diagnostic::error(format!("`@{value}` doesn't fit in 15 bits"), span)
+                                .with_hint(ADDRESS_RANGE),
+                        );
+                    }
+
+                    self.spans.push(span);
+                    self.instructions.push(Instruction::Addr(addr));
+                }
+
+                Some(Token::Dest(dest)) => {
+                    let start = lexer.span();
+
+                    // the lexer has already reported why the instruction is invalid
+                    let comp = lexer.next();
+                    if let Some(Token::Invalid) = comp {
+                        continue;
+                    }
+
+                    let jump = lexer.next();
+                    if let Some(Token::Invalid) = jump {
+                        continue;
+                    }
+
+                    if let (Some(Token::Comp(comp)), Some(Token::Jump(jump))) = (comp, jump) {
+                        let end = lexer.span();
+                        let span = Span {
+                            end: end.end,
+                            ..start
+                        };
+
+                        if comp.is_shift() && self.isa != Isa::Extended {
+                            self.diagnostics.push(
+                                Diagnostic::error(
+                                    format!("`{comp}` is only in the extended instruction set"),
+                                    span,
+                                )
+                                .with_hint("assemble with `--isa extended` to use shifts"),
+                            );
+                        }
+
+                        self.spans.push(span);
+                        self.instructions.push(Instruction::Comp(dest, comp, jump));
+                    } else {
+                        let message = match dest {
+                            DestToken::Empty => "incomplete instruction".to_string(),
+                            dest => format!("incomplete instruction after `{dest}=`"),
+                        };
+
+                        self.diagnostics
+                            .push(Diagnostic::error(message, lexer.span()));
+                    }
+                }
+
+                Some(Token::Layout(layout, mut expr)) => {
+                    let span = lexer.span();
+
+                    for symbol in expr.symbols_mut() {
+                        self.resolve_local(&mut scopes, symbol, span);
+                    }
+
+                    self.pad(layout, &expr, span);
+                }
+
+                Some(Token::Data(name)) => self.define_table(name.to_string(), lexer.span()),
+
+                Some(Token::Words(mut words)) => {
+                    let span = lexer.span();
+
+                    for symbol in words.iter_mut().flat_map(Expr::symbols_mut) {
+                        self.resolve_local(&mut scopes, symbol, span);
+                    }
+
+                    match self.tables.last_mut() {
+                        Some(table) => table.words.extend(words.into_iter().map(|w| (w, span))),
+                        None => self.diagnostics.push(
+                            Diagnostic::error("values outside a `.data` table".to_string(), span)
+                                .with_hint("start a table with `.data NAME` before them"),
+                        ),
+                    }
+                }
+
+                Some(Token::Invalid) => (),
+
+                Some(token) => {
+                    self.diagnostics.push(Diagnostic::error(
+                        format!("unexpected `{token}`"),
+                        lexer.span(),
+                    ));
+                }
+
+                None => break,
+            }
+        }
+
+        for (label, written, span) in scopes.references {
+            if self.labels.contains_key(&label) {
+                continue;
+            }
+
+            let message = match written.split_at(written.len() - 1) {
+                (n, "f") if n.bytes().all(|byte| byte.is_ascii_digit()) => {
+                    format!("there's no `{n}:` label after `@{written}`")
+                }
+                _ => format!("the local label `{written}` isn't defined here"),
+            };
+
+            self.diagnostics.push(Diagnostic::error(message, span));
+        }
+
+        // everything above is in terms of the preprocessor's output, so map it back to the
+        // original source. the preprocessor's own diagnostics already are.
+        let mut diagnostics = lexer.into_diagnostics();
+        self.expansion.text = text;
+
+        diagnostics.extend(self.diagnostics.drain(preprocessed..));
+
+        for diagnostic in diagnostics {
+            let origin = self.expansion.origin(diagnostic.span.line);
+            let mut diagnostic = diagnostic;
+
+            diagnostic.span = origin.locate(diagnostic.span);
+            diagnostic.notes.extend(origin.notes());
+            self.diagnostics.push(diagnostic);
+        }
+
+        // instructions expanded from a macro are attributed to the line that invoked it
+        let definitions = self.definitions.values_mut().map(|(_, span)| span);
+        let layout = self.layout.iter_mut().map(|(_, span, _)| span);
+        let tables = self.tables.iter_mut().flat_map(|table| {
+            std::iter::once(&mut table.span).chain(table.words.iter_mut().map(|(_, span)| span))
+        });
+
+        for span in self
+            .spans
+            .iter_mut()
+            .chain(self.labels.values_mut())
+            .chain(definitions)
+            .chain(layout)
+            .chain(tables)
+        {
+            *span = self.expansion.origin(span.line).locate_call_site(*span);
+        }
+
+        self.split_names();
+        self.check_rom_size();
+        self
+    }
+
+    // pads ROM for `.org`, `.fill` and `.align`. the operand is evaluated right away, so it can
+    // only use the constants and labels above it.
+    fn pad(&mut self, layout: Layout, expr: &Expr, span: Span) {
+        let mut values = HashMap::new();
+        let value = expr.eval(&mut |symbol| {
+            evaluate_constant(
+                symbol,
+                &self.definitions,
+                &self.symbols,
+                &mut values,
+                &mut vec![],
+            )
+        });
+
+        let value = match value {
+            Ok(value) => value,
+            Err(message) => {
+                self.diagnostics
+                    .push(Diagnostic::error(message, span).with_hint(format!(
+                        "`{layout}` can only use the constants and labels above it"
+                    )));
+                return;
+            }
+        };
+
+        let here = self.instructions.len() as i64;
+        let words = match layout {
+            Layout::Org if value < here => {
+                self.diagnostics.push(
+                    Diagnostic::error(format!("`.org {value}` would move backwards"), span)
+                        .with_hint(format!("the code above already reaches address {here}")),
+                );
+                return;
+            }
+            Layout::Org => value - here,
+            Layout::Fill if value < 0 => {
+                self.diagnostics.push(Diagnostic::error(
+                    format!("`.fill` can't pad with {value} words"),
+                    span,
+                ));
+                return;
+            }
+            Layout::Fill => value,
+            Layout::Align if value <= 0 => {
+                self.diagnostics.push(Diagnostic::error(
+                    format!("`.align` can't align to multiples of {value}"),
+                    span,
+                ));
+                return;
+            }
+            Layout::Align => (value - here % value) % value,
+        };
+
+        // checked here rather than by check_rom_size so a typo doesn't fill memory
+        if here + words > ROM_SIZE as i64 {
+            self.diagnostics.push(
+                Diagnostic::error(format!("`{layout}` pads past the end of ROM"), span).with_hint(
+                    format!(
+                        "ROM holds {ROM_SIZE} words, and this would need {}",
+                        here + words
+                    ),
+                ),
+            );
+            return;
+        }
+
+        let start = self.instructions.len();
+
+        for _ in 0..words {
+            self.instructions.push(self.padding.clone());
+            self.spans.push(span);
+        }
+
+        self.layout
+            .push((layout, span, start..self.instructions.len()));
+    }
+
+    /// Whether the instruction at `address` was padding from `.org`, `.fill` or `.align`.
+    pub(crate) fn is_padding(&self, address: usize) -> bool {
+        self.layout
+            .iter()
+            .any(|(_, _, words)| words.contains(&address))
+    }
+
+    pub(crate) fn check_rom_size(&mut self) {
+        if self.instructions.len() > ROM_SIZE {
+            let diagnostic = Diagnostic::error(
+                format!(
+                    "the program is {} words long, which doesn't fit in ROM",
+                    self.instructions.len()
+                ),
+                self.spans[ROM_SIZE],
+            );
+
+            self.diagnostics.push(diagnostic.with_hint(format!(
+                "ROM holds {ROM_SIZE} words, and this is the first instruction past the end"
+            )));
+        }
+    }
+
+    fn define_label(&mut self, label: String, span: Span) {
+        if self.symbols.contains_key(&label) || self.first_definition(&label).is_some() {
+            let mut diagnostic =
+                Diagnostic::error(format!("the ({label}) label is defined twice"), span);
+
+            diagnostic = match self.first_definition(&label) {
+                Some(first) => {
+                    let first = self.expansion.origin(first.line).locate(first);
+                    let file = self.sources.name(first.file);
+
+                    diagnostic
+                        .with_hint(format!("first defined in {file}"))
+                        .with_note(first, "first defined here")
+                }
+                None => diagnostic.with_hint(builtin(&label)),
+            };
+
+            self.diagnostics.push(diagnostic);
+        } else {
+            self.labels.insert(label.clone(), span);
+            self.symbols.insert(label, self.instructions.len());
+        }
+    }
+
+    // renames a reference to a local or numeric label to the label it refers to
+    fn resolve_local(&mut self, scopes: &mut Scopes, name: &mut String, span: Span) {
+        match scopes.resolve(name, span) {
+            Ok(Some(label)) => *name = label,
+            Ok(None) => (),
+            Err(message) => self.diagnostics.push(Diagnostic::error(message, span)),
+        }
+    }
+
+    // labels can have `-` in them, like `(END-OF-PROGRAM)`, so `@a-b` is the name `a-b` when
+    // something defines it, and otherwise `a` minus `b` when both are defined. anything else is
+    // left as a name, and becomes a variable if nothing else defines it. a relocatable object
+    // doesn't know every name, so the linker does this again.
+    pub(crate) fn split_names(&mut self) {
+        let plain: HashSet<String> = self
+            .instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Addr(AddrToken::Dynamic(name)) if !name.contains('-') => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect();
+
+        let defined = |name: &str| {
+            self.symbols.contains_key(name)
+                || self.definitions.contains_key(name)
+                || self.table(name).is_some()
+                || plain.contains(name)
+        };
+
+        let splits: Vec<(usize, Expr)> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instruction)| match instruction {
+                Instruction::Addr(AddrToken::Dynamic(name))
+                    if name.contains('-') && !defined(name) =>
+                {
+                    let expr = Expr::parse(name).ok()?;
+                    let parts = expr.symbols().into_iter().all(&defined);
+                    parts.then_some((i, expr))
+                }
+                _ => None,
+            })
+            .collect();
+
+        for (i, expr) in splits {
+            self.instructions[i] = Instruction::Addr(AddrToken::Expr(expr));
+        }
+    }
+
+    // where a label or constant was first defined, in terms of the preprocessor's output
+    fn first_definition(&self, name: &str) -> Option<Span> {
+        match self.labels.get(name) {
+            Some(span) => Some(*span),
+            None => self
+                .definitions
+                .get(name)
+                .map(|(_, span)| *span)
+                .or_else(|| self.table(name).map(|table| table.span)),
+        }
+    }
+
+    pub(crate) fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|table| table.name == name)
+    }
+
+    fn define_table(&mut self, name: String, span: Span) {
+        if let Some(first) = self.first_definition(&name) {
+            let first = self.expansion.origin(first.line).locate(first);
+
+            self.diagnostics.push(
+                Diagnostic::error(format!("`{name}` is already defined"), span)
+                    .with_note(first, "first defined here"),
+            );
+        } else if self.symbols.contains_key(&name) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("`{name}` is already defined"), span)
+                    .with_hint(builtin(&name)),
+            );
+        } else {
+            self.tables.push(Table {
+                name,
+                span,
+                words: vec![],
+            });
+        }
+    }
+
+    // fills in the `.data` tables at the start of ROM, before the rest of the program runs,
+    // moving the program's labels along to make room. each word is stored with `@table+i` and
+    // `M=...`, loading D first unless it already holds the value or is one away from it, or the
+    // value is 0, 1 or -1.
+    fn add_prologue(&mut self) {
+        let Some(first) = self.tables.first() else {
+            return;
+        };
+
+        if let Some((layout, span, _)) = self
+            .layout
+            .iter()
+            .find(|(layout, _, _)| *layout != Layout::Fill)
+        {
+            self.diagnostics.push(
+                Diagnostic::error(format!("`.data` tables can't be used with `{layout}`"), first.span)
+                    .with_hint("tables are filled in by code at the start of ROM, which would move the code it places")
+                    .with_note(*span, format!("`{layout}` is used here")),
+            );
+            return;
+        }
+
+        let mut instructions = vec![];
+        let mut spans = vec![];
+        // what D holds, when it's known
+        let mut d: Option<i64> = None;
+
+        for table in &self.tables {
+            for (i, (expr, span)) in table.words.iter().enumerate() {
+                let address = match i {
+                    0 => Expr::Symbol(table.name.clone()),
+                    i => Expr::Binary(
+                        BinaryOp::Add,
+                        Box::new(Expr::Symbol(table.name.clone())),
+                        Box::new(Expr::Number(i as i64)),
+                    ),
+                };
+
+                // values are stored as 16-bit words, so 65535 and -1 are the same thing
+                let value = match expr.eval(&mut |_| Err(String::new())) {
+                    Ok(value @ -32768..=65535) => Some(value as u16 as i16 as i64),
+                    Ok(value) => {
+                        self.diagnostics.push(Diagnostic::error(
+                            format!("{value} doesn't fit in a 16-bit word"),
+                            *span,
+                        ));
+                        continue;
+                    }
+                    Err(_) => None,
+                };
+
+                let (load, store): (Vec<Instruction>, CompToken) = match value {
+                    Some(0) => (vec![], CompToken::Zero),
+                    Some(1) => (vec![], CompToken::One),
+                    Some(-1) => (vec![], CompToken::Negate1),
+                    Some(value) if d == Some(value) => (vec![], CompToken::D),
+                    Some(value) if d == Some(value - 1) => (vec![], CompToken::AddD1),
+                    Some(value) if d == Some(value + 1) => (vec![], CompToken::SubD1),
+                    Some(value) if value >= 0 => (
+                        vec![
+                            Instruction::Addr(AddrToken::Static(value as usize)),
+                            Instruction::Comp(DestToken::D, CompToken::A, JumpToken::Empty),
+                        ],
+                        CompToken::D,
+                    ),
+                    Some(value) => (
+                        vec![
+                            Instruction::Addr(AddrToken::Static(!value as usize)),
+                            Instruction::Comp(DestToken::D, CompToken::InvertA, JumpToken::Empty),
+                        ],
+                        CompToken::D,
+                    ),
+                    None => (
+                        vec![
+                            Instruction::Addr(AddrToken::Expr(expr.clone())),
+                            Instruction::Comp(DestToken::D, CompToken::A, JumpToken::Empty),
+                        ],
+                        CompToken::D,
+                    ),
+                };
+
+                // D+1 and D-1 are stored in D too, so runs like strings keep counting
+                let dest = match store {
+                    CompToken::AddD1 | CompToken::SubD1 => DestToken::MD,
+                    _ => DestToken::M,
+                };
+
+                if !load.is_empty() || dest == DestToken::MD {
+                    d = value;
+                }
+
+                instructions.extend(load);
+                instructions.push(Instruction::Addr(AddrToken::Expr(address)));
+                instructions.push(Instruction::Comp(dest, store, JumpToken::Empty));
+                spans.resize(instructions.len(), *span);
+            }
+        }
+
+        let words = instructions.len();
+
+        for label in self.labels.keys() {
+            *self.symbols.get_mut(label).unwrap() += words;
+        }
+
+        for (_, _, padding) in &mut self.layout {
+            *padding = padding.start + words..padding.end + words;
+        }
+
+        self.instructions.splice(0..0, instructions);
+        self.spans.splice(0..0, spans);
+        self.check_rom_size();
+    }
+
+    pub(crate) fn finish(mut self) -> Self {
+        for diagnostic in self.diagnostics.iter_mut().chain(self.warnings.iter_mut()) {
+            diagnostic.file = Some(self.sources.name(diagnostic.span.file).to_string());
+        }
+
+        self.diagnostics.sort_by_key(|diagnostic| diagnostic.span);
+        self.warnings.sort_by_key(|warning| warning.span);
+
+        self
+    }
+
+    // tables are allocated first, and constants are evaluated next, since they can only refer to
+    // labels, tables and each other. plain `@name` operands come next, allocating variables, and
+    // then the remaining expressions, which may refer to anything.
+    pub(crate) fn second_pass(mut self) -> Self {
+        let mut values: HashMap<String, i64> = HashMap::new();
+
+        self.references = self
+            .instructions
+            .iter()
+            .map(|instruction| match instruction {
+                Instruction::Addr(AddrToken::Dynamic(name)) => vec![name.clone()],
+                Instruction::Addr(AddrToken::Expr(expr)) => {
+                    expr.symbols().into_iter().map(String::from).collect()
+                }
+                _ => vec![],
+            })
+            .collect();
+
+        let mut next_dynamic_address = VARIABLES.start() - 1;
+
+        for table in &self.tables {
+            if next_dynamic_address + table.words.len() > *VARIABLES.end() {
+                self.diagnostics.push(
+                    Diagnostic::error(
+                        format!("there's no room left in RAM for the `{}` table", table.name),
+                        table.span,
+                    )
+                    .with_hint(format!(
+                        "tables and variables are allocated from RAM[{}] to RAM[{}]",
+                        VARIABLES.start(),
+                        VARIABLES.end(),
+                    )),
+                );
+            }
+
+            self.symbols
+                .insert(table.name.clone(), next_dynamic_address + 1);
+            next_dynamic_address += table.words.len();
+        }
+
+        for name in &self.constants {
+            let value = evaluate_constant(
+                name,
+                &self.definitions,
+                &self.symbols,
+                &mut values,
+                &mut vec![],
+            );
+
+            if let Err(message) = value {
+                let span = self.definitions[name].1;
+                self.diagnostics.push(Diagnostic::error(
+                    format!("couldn't evaluate `{name}`: {message}"),
+                    span,
+                ));
+            }
+        }
+
+        for (i, instruction) in self.instructions.iter_mut().enumerate() {
+            let Instruction::Addr(AddrToken::Dynamic(label)) = instruction else {
+                continue;
+            };
+
+            if let Some(&value) = self.symbols.get(label.as_str()) {
+                *instruction = Instruction::Addr(AddrToken::Static(value));
+            } else if let Some(&value) = values.get(label.as_str()) {
+                if let Some(value) = address(label, value, self.spans[i], &mut self.diagnostics) {
+                    *instruction = Instruction::Addr(AddrToken::Static(value));
+                }
+            } else if !self.definitions.contains_key(label.as_str()) {
+                next_dynamic_address += 1;
+
+                if next_dynamic_address > *VARIABLES.end() {
+                    let diagnostic = Diagnostic::error(
+                        format!("there's no room left in RAM for the variable `{label}`"),
+                        self.spans[i],
+                    );
+
+                    self.diagnostics.push(diagnostic.with_hint(format!(
+                        "variables are allocated from RAM[{}] to RAM[{}], and the screen starts \
+                         at RAM[{}]",
+                        VARIABLES.start(),
+                        VARIABLES.end(),
+                        VARIABLES.end() + 1
+                    )));
+                }
+
+                self.variables.push(label.clone());
+                self.symbols.insert(label.clone(), next_dynamic_address);
+                *instruction = Instruction::Addr(AddrToken::Static(next_dynamic_address));
+            }
+        }
+
+        for (i, instruction) in self.instructions.iter_mut().enumerate() {
+            let Instruction::Addr(AddrToken::Expr(expr)) = instruction else {
+                continue;
+            };
+
+            let value = expr.eval(&mut |name| match self.symbols.get(name) {
+                Some(&value) => Ok(value as i64),
+                None => values
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| format!("`{name}` is not defined")),
+            });
+
+            let text = expr.to_string();
+
+            match value {
+                Ok(value) => {
+                    if let Some(value) = address(&text, value, self.spans[i], &mut self.diagnostics)
+                    {
+                        *instruction = Instruction::Addr(AddrToken::Static(value));
+                    }
+                }
+                Err(message) => self.diagnostics.push(Diagnostic::error(
+                    format!("couldn't evaluate `{text}`: {message}"),
+                    self.spans[i],
+                )),
+            }
+        }
+
+        // constants only appear in the symbol table when they could be used as an address
+        for (name, value) in values {
+            if value >= 0 {
+                self.symbols.insert(name, value as usize);
+            }
+        }
+
+        self
+    }
+}
+
+/// A `.data` table, which is allocated in RAM like a variable and filled in by the prologue.
+pub(crate) struct Table {
+    pub name: String,
+    pub span: Span,
+    /// The values from its `.word`s and `.string`s, with where each came from.
+    pub words: Vec<(Expr, Span)>,
+}
+
+// local labels, like `(.loop)`, belong to the global label before them and are known as
+// `GLOBAL.loop` outside of it. numeric labels, like `1:`, can be defined any number of times;
+// `@1b` refers to the closest one before and `@1f` to the closest one after. each definition
+// gets a name, `1:0`, `1:1` and so on, that can't be written in the source.
+#[derive(Default)]
+struct Scopes {
+    global: String,
+    numeric: HashMap<usize, usize>,
+    /// References that have to be checked once every label is known: the label's name, how it
+    /// was written and where.
+    references: Vec<(String, String, Span)>,
+}
+
+impl Scopes {
+    fn define(&mut self, label: String, expanded: bool) -> String {
+        if label.starts_with('.') {
+            format!("{}{label}", self.global)
+        } else {
+            if !expanded {
+                self.global = label.clone();
+            }
+
+            label
+        }
+    }
+
+    fn define_numeric(&mut self, n: usize) -> String {
+        let count = self.numeric.entry(n).or_default();
+        *count += 1;
+
+        format!("{n}:{}", *count - 1)
+    }
+
+    // the label a reference refers to, if it's to a local or numeric label
+    fn resolve(&mut self, name: &str, span: Span) -> Result<Option<String>, String> {
+        let label = if name.starts_with('.') {
+            format!("{}{name}", self.global)
+        } else if let Some(n) = name.strip_suffix('b').and_then(|n| n.parse::<usize>().ok()) {
+            match self.numeric.get(&n) {
+                Some(count) => return Ok(Some(format!("{n}:{}", count - 1))),
+                None => return Err(format!("there's no `{n}:` label before `@{name}`")),
+            }
+        } else if let Some(n) = name.strip_suffix('f').and_then(|n| n.parse::<usize>().ok()) {
+            format!("{n}:{}", self.numeric.get(&n).copied().unwrap_or(0))
+        } else {
+            return Ok(None);
+        };
+
+        self.references
+            .push((label.clone(), name.to_string(), span));
+        Ok(Some(label))
+    }
+}
+
+/// Evaluates the constant `name`, and the ones it's defined in terms of, remembering their
+/// values in `values`.
+pub(crate) fn evaluate_constant(
+    name: &str,
+    definitions: &HashMap<String, (Expr, Span)>,
+    symbols: &HashMap<String, usize>,
+    values: &mut HashMap<String, i64>,
+    evaluating: &mut Vec<String>,
+) -> Result<i64, String> {
+    if let Some(&value) = values.get(name) {
+        return Ok(value);
+    }
+
+    if let Some(&value) = symbols.get(name) {
+        return Ok(value as i64);
+    }
+
+    let Some((expr, _)) = definitions.get(name) else {
+        return Err(format!("`{name}` is not a label or constant"));
+    };
+
+    if evaluating.iter().any(|outer| outer == name) {
+        return Err(format!("`{name}` is defined in terms of itself"));
+    }
+
+    evaluating.push(name.to_string());
+    let value = expr
+        .eval(&mut |symbol| evaluate_constant(symbol, definitions, symbols, values, evaluating));
+    evaluating.pop();
+
+    let value = value?;
+    values.insert(name.to_string(), value);
+    Ok(value)
+}
+
+// why a symbol that was never defined in the program exists anyway
+fn builtin(name: &str) -> String {
+    if PREDEFINED_SYMBOLS.iter().any(|(symbol, _)| *symbol == name) {
+        format!("`{name}` is a predefined symbol")
+    } else {
+        format!("`{name}` was defined with `-D`")
+    }
+}
+
+// A-instructions load 15-bit values, so anything else is reported and left unresolved
+fn address(text: &str, value: i64, span: Span, diagnostics: &mut Vec<Diagnostic>) -> Option<usize> {
+    if (0..ROM_SIZE as i64).contains(&value) {
+        return Some(value as usize);
+    }
+
+    diagnostics.push(
+        Diagnostic::error(
+            format!("`{text}` evaluates to {value}, which doesn't fit in 15 bits"),
+            span,
+        )
+        .with_hint(ADDRESS_RANGE),
+    );
+
+    None
+}
+
+impl IntoIterator for Parser {
+    type Item = Instruction;
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.instructions.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_every_error() {
+        let diagnostics = match Parser::from_str("D=X\n@1\n0;JMX\n(L)\n(L)\n") {
+            Ok(_) => panic!("expected the source to be rejected"),
+            Err(diagnostics) => diagnostics,
+        };
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.span.line).collect();
+
+        assert_eq!(lines, vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn pads_rom_for_org_fill_and_align() {
+        let source =
+            ".equ TABLE 4\n@MAIN\n0;JMP\n.org TABLE\n(TABLE0)\n.fill 1\n.align 4\n(MAIN)\n";
+        let parser = Parser::from_str(source).unwrap();
+        let words: Vec<_> = parser
+            .instructions
+            .iter()
+            .map(Instruction::encode)
+            .collect();
+
+        assert_eq!(
+            words,
+            [8, 0xea87, 0xea80, 0xea80, 0xea80, 0xea80, 0xea80, 0xea80]
+        );
+        assert_eq!(parser.symbols["TABLE0"], 4);
+        assert!(parser
+            .warnings
+            .iter()
+            .all(|w| w.message != "unreachable instruction"));
+
+        let diagnostics = match Parser::from_str("@0\n@1\n.org 1\n.align LATER\n(LATER)\n") {
+            Ok(_) => panic!("expected the source to be rejected"),
+            Err(diagnostics) => diagnostics,
+        };
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            [
+                "`.org 1` would move backwards",
+                "`LATER` is not a label or constant"
+            ]
+        );
+    }
+
+    #[test]
+    fn fills_data_tables_in_a_prologue() {
+        let source = "(START)\n@x\n.data T\n.word 5, 6, 0\n.string \"a\"\n@T\n";
+        let parser = Parser::from_str(source).unwrap();
+        let words: Vec<_> = parser
+            .instructions
+            .iter()
+            .map(Instruction::encode)
+            .collect();
+
+        assert_eq!(
+            words,
+            [
+                5, 0xec10, 16, 0xe308, 17, 0xe7d8, 18, 0xea88, 97, 0xec10, 19, 0xe308, 20, 0xea88,
+                21, 16
+            ]
+        );
+        assert_eq!(parser.symbols["START"], 14);
+        assert_eq!(parser.symbols["x"], 21);
+
+        let diagnostics = match Parser::from_str(".word 1\n(T)\n.data T\n") {
+            Ok(_) => panic!("expected the source to be rejected"),
+            Err(diagnostics) => diagnostics,
+        };
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            ["values outside a `.data` table", "`T` is already defined"]
+        );
+    }
+}
@@ -0,0 +1,836 @@
+// the preprocessor works a line at a time, before the lexer ever sees the source. it handles the
+// directives that change which lines get assembled (.include, .macro/.endm and macro
+// invocations, control flow blocks and conditional assembly) and produces the expanded text the
+// lexer runs over, plus an Origin for every expanded line so spans in the expanded text can be
+// traced back to the original source.
+//
+// `.if` is both a control flow block and conditional assembly: `.if D>0` compares a computation
+// with 0 at run time, and anything else, like `.if LEVEL>1`, is a constant expression decided
+// while preprocessing. conditions can use predefined symbols, `-D` defines and the `.equ`
+// constants above them. an `.else` or `.endif` belongs to whichever kind of `.if` was opened
+// last.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::control::{self, Blocks};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expr::Expr;
+use crate::lexer::comment_start;
+use crate::parser::{evaluate_constant, PREDEFINED_SYMBOLS};
+use crate::source::Sources;
+
+/// Where a line of expanded text came from.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Origin {
+    pub file: usize,
+    /// The line in the original source.
+    pub line: usize,
+    /// The columns to underline instead of the expanded line's own columns, set when parameter
+    /// substitution or label renaming moved things around.
+    pub columns: Option<(usize, usize)>,
+    /// The macro invocations this line was expanded from, innermost first.
+    pub calls: Vec<(String, Span)>,
+    /// Set on the lines a control flow directive was lowered into.
+    pub generated: bool,
+}
+
+impl Origin {
+    /// Maps a span in the expanded text back to the original source.
+    pub fn locate(&self, span: Span) -> Span {
+        match self.columns {
+            Some((start, end)) => Span::new(self.line, start, end).in_file(self.file),
+            None => Span::new(self.line, span.start, span.end).in_file(self.file),
+        }
+    }
+
+    /// Like locate, but lines expanded from a macro map to the outermost invocation instead.
+    pub fn locate_call_site(&self, span: Span) -> Span {
+        match self.calls.last() {
+            Some((_, call)) => *call,
+            None => self.locate(span),
+        }
+    }
+
+    pub fn notes(&self) -> Vec<(Span, String)> {
+        self.calls
+            .iter()
+            .map(|(name, span)| (*span, format!("in this expansion of `{name}`")))
+            .collect()
+    }
+}
+
+/// The result of preprocessing a program.
+#[derive(Debug, Default)]
+pub(crate) struct Expansion {
+    pub text: String,
+    /// One entry per line of `text`.
+    pub origins: Vec<Origin>,
+}
+
+impl Expansion {
+    pub fn origin(&self, line: usize) -> Origin {
+        self.origins.get(line - 1).cloned().unwrap_or(Origin {
+            line,
+            ..Origin::default()
+        })
+    }
+}
+
+struct Macro {
+    file: usize,
+    params: Vec<String>,
+    body: Vec<(usize, String)>,
+    labels: Vec<String>,
+}
+
+/// An `.if`, `.ifdef` or `.ifndef` that's decided while preprocessing.
+struct Conditional {
+    directive: String,
+    /// Whether the lines around it are assembled. When they aren't, neither branch is.
+    outer: bool,
+    /// Whether the condition held.
+    held: bool,
+    /// Whether the lines in the current branch are assembled.
+    active: bool,
+    has_else: bool,
+    /// How many control flow blocks were open when it started, so an `.else` or `.endif` inside
+    /// a block opened after it goes to the block instead.
+    blocks: usize,
+    span: Span,
+    context: usize,
+}
+
+pub(crate) struct Preprocessor<'a> {
+    sources: &'a mut Sources,
+    // the files currently being preprocessed, outermost first, with the .include that pulled
+    // each one in
+    includes: Vec<(usize, Option<Span>)>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    blocks: Blocks,
+    // the file or macro expansion being preprocessed, which control flow blocks have to be
+    // closed in, and how many there have been
+    context: usize,
+    contexts: usize,
+    conditionals: Vec<Conditional>,
+    // what conditions can refer to besides the `.equ` constants defined so far
+    symbols: HashMap<String, usize>,
+    constants: HashMap<String, (Expr, Span)>,
+    output: Expansion,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Preprocessor<'a> {
+    /// Preprocesses every root file in `sources`, one after the other. Included files are added
+    /// to `sources` as they're read, and `defines` can be tested by conditional assembly.
+    pub fn run(
+        sources: &'a mut Sources,
+        defines: &[(String, usize)],
+    ) -> (Expansion, Vec<Diagnostic>) {
+        let roots = sources.roots().to_vec();
+        let symbols = PREDEFINED_SYMBOLS
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .chain(defines.iter().cloned())
+            .collect();
+        let mut preprocessor = Self {
+            sources,
+            includes: vec![],
+            macros: HashMap::new(),
+            expansions: 0,
+            blocks: Blocks::default(),
+            context: 0,
+            contexts: 0,
+            conditionals: vec![],
+            symbols,
+            constants: HashMap::new(),
+            output: Expansion::default(),
+            diagnostics: vec![],
+        };
+
+        for file in roots {
+            preprocessor.file(file, None);
+        }
+
+        (preprocessor.output, preprocessor.diagnostics)
+    }
+
+    fn file(&mut self, file: usize, included_at: Option<Span>) {
+        let Some(source) = self.sources.get(file) else {
+            return;
+        };
+        let source = source.text.clone();
+        let mut lines = source.lines().enumerate().map(|(n, text)| (n + 1, text));
+
+        self.includes.push((file, included_at));
+        let outer = self.enter();
+
+        while let Some((line, text)) = lines.next() {
+            let origin = Origin {
+                file,
+                line,
+                ..Origin::default()
+            };
+
+            if self.conditional(line, text, &origin) {
+                continue;
+            }
+
+            match directive(text) {
+                Some((".include", _)) => self.include(file, line, text),
+                Some((".macro", args)) => self.define(file, line, text, args, &mut lines),
+                Some((".endm", _)) => self.diagnostics.push(Diagnostic::error(
+                    "`.endm` without a matching `.macro`".to_string(),
+                    word_span(line, text, ".endm").in_file(file),
+                )),
+                _ => self.process(line, text, origin),
+            }
+        }
+
+        self.leave(outer);
+        self.includes.pop();
+    }
+
+    // starts a new context for control flow blocks, returning the one it replaces
+    fn enter(&mut self) -> usize {
+        self.contexts += 1;
+        std::mem::replace(&mut self.context, self.contexts)
+    }
+
+    // reports the blocks and conditionals that were left open in the current context before
+    // going back to `outer`
+    fn leave(&mut self, outer: usize) {
+        let unclosed = self.blocks.unclosed(self.context);
+        self.diagnostics.extend(unclosed);
+
+        while let Some(conditional) = self
+            .conditionals
+            .pop_if(|conditional| conditional.context == self.context)
+        {
+            self.diagnostics.push(
+                Diagnostic::error(
+                    format!("the `{}` is never closed", conditional.directive),
+                    conditional.span,
+                )
+                .with_hint("end it with `.endif`"),
+            );
+        }
+
+        self.context = outer;
+    }
+
+    fn active(&self) -> bool {
+        self.conditionals
+            .last()
+            .is_none_or(|conditional| conditional.active)
+    }
+
+    // handles conditional assembly directives, and skips the lines they leave out. returns
+    // whether the line has been dealt with.
+    fn conditional(&mut self, line: usize, text: &str, origin: &Origin) -> bool {
+        let active = self.active();
+        let Some((name, args)) = directive(text) else {
+            return !active;
+        };
+        let span = origin.locate(word_span(line, text, name));
+        let owned = self.conditionals.last().is_some_and(|conditional| {
+            conditional.context == self.context && conditional.blocks == self.blocks.depth()
+        });
+
+        let held = match name {
+            // nothing inside a branch that's left out is looked at, besides how it nests
+            ".ifdef" | ".ifndef" | ".if" if !active => false,
+            ".ifdef" | ".ifndef" => {
+                self.defined(name, &args, span, origin) == Some(name == ".ifdef")
+            }
+            ".if" if control::parse_condition(&args.concat()).is_none() => {
+                self.condition(&args.concat(), span, origin)
+            }
+            ".else" | ".endif" if owned => {
+                self.branch(name, span, origin);
+                return true;
+            }
+            _ => return !active,
+        };
+
+        self.conditionals.push(Conditional {
+            directive: name.to_string(),
+            outer: active,
+            held,
+            active: held,
+            has_else: false,
+            blocks: self.blocks.depth(),
+            span,
+            context: self.context,
+        });
+
+        true
+    }
+
+    // whether the name after an `.ifdef` or `.ifndef` is defined, if there is one
+    fn defined(&mut self, name: &str, args: &[&str], span: Span, origin: &Origin) -> Option<bool> {
+        match args {
+            [symbol] if is_identifier(symbol) => {
+                Some(self.symbols.contains_key(*symbol) || self.constants.contains_key(*symbol))
+            }
+            _ => {
+                let diagnostic =
+                    Diagnostic::error(format!("expected one name after `{name}`"), span)
+                        .with_hint(format!("e.g. `{name} DEBUG`"));
+                self.report(diagnostic, origin);
+                None
+            }
+        }
+    }
+
+    // evaluates the constant condition after an `.if`, which doesn't hold if it's invalid
+    fn condition(&mut self, text: &str, span: Span, origin: &Origin) -> bool {
+        const HINT: &str = "conditions compare constants, like `.if LEVEL>1`, or a computation \
+                            with 0, like `.if D>0`";
+
+        let expr = match Expr::parse_comparison(text) {
+            Ok(expr) => expr,
+            Err(_) => {
+                let message = if text.is_empty() {
+                    "expected a condition after `.if`".to_string()
+                } else {
+                    format!("`{text}` is not a condition")
+                };
+                self.report(Diagnostic::error(message, span).with_hint(HINT), origin);
+                return false;
+            }
+        };
+
+        let mut values = HashMap::new();
+        let value = expr.eval(&mut |symbol| {
+            evaluate_constant(
+                symbol,
+                &self.constants,
+                &self.symbols,
+                &mut values,
+                &mut vec![],
+            )
+        });
+
+        match value {
+            Ok(value) => value != 0,
+            Err(message) => {
+                let diagnostic = Diagnostic::error(message, span).with_hint(
+                    "conditions can use predefined symbols, `-D` defines and the `.equ` \
+                     constants above them",
+                );
+                self.report(diagnostic, origin);
+                false
+            }
+        }
+    }
+
+    // switches to the `.else` branch of the innermost conditional, or closes it
+    fn branch(&mut self, name: &str, span: Span, origin: &Origin) {
+        if name == ".endif" {
+            self.conditionals.pop();
+            return;
+        }
+
+        let conditional = self.conditionals.last_mut().unwrap();
+
+        if conditional.has_else {
+            let diagnostic = Diagnostic::error(
+                format!("this `{}` already has an `.else`", conditional.directive),
+                span,
+            )
+            .with_note(
+                conditional.span,
+                format!("the `{}` starts here", conditional.directive),
+            );
+            self.report(diagnostic, origin);
+            return;
+        }
+
+        conditional.has_else = true;
+        conditional.active = conditional.outer && !conditional.held;
+    }
+
+    // remembers an `.equ` constant so later conditions can use it. the parser reports anything
+    // wrong with the definition.
+    fn remember_constant(&mut self, line: usize, text: &str) {
+        if let Some((".equ", args)) = directive(text) {
+            if let [name, value @ ..] = args.as_slice() {
+                if let Ok(expr) = Expr::parse(&value.concat()) {
+                    let span = word_span(line, text, name);
+                    self.constants
+                        .entry(name.to_string())
+                        .or_insert((expr, span));
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, mut diagnostic: Diagnostic, origin: &Origin) {
+        diagnostic.notes.extend(origin.notes());
+        self.diagnostics.push(diagnostic);
+    }
+
+    // paths are relative to the including file. a file can be included more than once, but not
+    // while it's already being preprocessed.
+    fn include(&mut self, file: usize, line: usize, text: &str) {
+        let span = word_span(line, text, ".include").in_file(file);
+        let argument = strip_comment(text)
+            .trim()
+            .trim_start_matches(".include")
+            .trim();
+        let path = match argument.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+            Some(path) if !path.is_empty() => path,
+            _ => {
+                self.diagnostics.push(
+                    Diagnostic::error("expected a quoted path after `.include`".to_string(), span)
+                        .with_hint("e.g. `.include \"math.asm\"`"),
+                );
+                return;
+            }
+        };
+        let span = word_span(line, text, argument).in_file(file);
+        let path = match self.sources.get(file) {
+            Some(source) => source.dir().join(path),
+            None => PathBuf::from(path),
+        };
+        let canonical = path.canonicalize().ok();
+
+        let cycle = self.includes.iter().position(|(including, _)| {
+            let including = self.sources.get(*including).and_then(|s| s.path.as_ref());
+            canonical.is_some() && including.and_then(|p| p.canonicalize().ok()) == canonical
+        });
+
+        if let Some(start) = cycle {
+            let mut diagnostic = Diagnostic::error(
+                format!("`{}` includes itself", path.to_string_lossy()),
+                span,
+            );
+
+            for (_, included_at) in &self.includes[start + 1..] {
+                if let Some(included_at) = included_at {
+                    diagnostic = diagnostic.with_note(*included_at, "included from here");
+                }
+            }
+
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+
+        match self.sources.read(&path) {
+            Ok(included) => self.file(included, Some(span)),
+            Err(e) => self.diagnostics.push(Diagnostic::error(
+                format!("couldn't read `{}`: {e}", path.to_string_lossy()),
+                span,
+            )),
+        }
+    }
+
+    fn define<'b>(
+        &mut self,
+        file: usize,
+        line: usize,
+        text: &str,
+        args: Vec<&str>,
+        lines: &mut impl Iterator<Item = (usize, &'b str)>,
+    ) {
+        let mut body = vec![];
+        let mut closed = false;
+
+        // the definition is consumed even when it's invalid so its body isn't assembled
+        for (body_line, body_text) in lines.by_ref() {
+            match directive(body_text) {
+                Some((".endm", _)) => {
+                    closed = true;
+                    break;
+                }
+                Some((".macro", _)) => self.diagnostics.push(Diagnostic::error(
+                    "macro definitions can't be nested".to_string(),
+                    word_span(body_line, body_text, ".macro").in_file(file),
+                )),
+                _ => body.push((body_line, body_text.to_string())),
+            }
+        }
+
+        let span = word_span(line, text, ".macro").in_file(file);
+
+        if !closed {
+            self.diagnostics.push(
+                Diagnostic::error("unterminated macro definition".to_string(), span)
+                    .with_hint("end the definition with `.endm`"),
+            );
+            return;
+        }
+
+        let Some((name, params)) = args.split_first() else {
+            self.diagnostics.push(
+                Diagnostic::error("expected a macro name after `.macro`".to_string(), span)
+                    .with_hint("e.g. `.macro PUSH value`"),
+            );
+            return;
+        };
+
+        for word in args.iter() {
+            if !is_identifier(word) {
+                self.diagnostics.push(Diagnostic::error(
+                    format!("`{word}` is not a valid macro or parameter name"),
+                    word_span(line, text, word).in_file(file),
+                ));
+                return;
+            }
+        }
+
+        if self.macros.contains_key(*name) {
+            self.diagnostics.push(Diagnostic::error(
+                format!("the `{name}` macro is defined twice"),
+                word_span(line, text, name).in_file(file),
+            ));
+            return;
+        }
+
+        let labels = body
+            .iter()
+            .filter_map(|(_, text)| label_definition(text))
+            .map(str::to_string)
+            .collect();
+
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                file,
+                params: params.iter().map(|param| param.to_string()).collect(),
+                body,
+                labels,
+            },
+        );
+    }
+
+    // emits a line, expanding it first if it invokes a macro
+    fn process(&mut self, line: usize, text: &str, origin: Origin) {
+        let code = strip_comment(text);
+        let mut words = code.split(|c: char| c.is_whitespace() || c == ',');
+        let name = words.find(|word| !word.is_empty()).unwrap_or("");
+
+        if !self.macros.contains_key(name) {
+            if name == ".include" && !origin.calls.is_empty() {
+                let span = origin.locate(word_span(line, text, name));
+                let mut diagnostic =
+                    Diagnostic::error("`.include` can't be used inside a macro".to_string(), span);
+                diagnostic.notes = origin.notes();
+                self.diagnostics.push(diagnostic);
+                return;
+            }
+
+            // control flow directives, which are the only ones handled here, ignore spacing
+            let args: String = words.collect();
+            let span = origin.locate(word_span(line, text, name));
+            let notes = origin.notes();
+            let mut diagnostics = vec![];
+
+            match self
+                .blocks
+                .lower(name, &args, span, self.context, &mut diagnostics)
+            {
+                Some(lines) => {
+                    let origin = Origin {
+                        columns: origin.columns.or(Some(trimmed_columns(text))),
+                        generated: true,
+                        ..origin
+                    };
+
+                    for line in lines {
+                        self.emit(&line, origin.clone());
+                    }
+                }
+                None => {
+                    self.remember_constant(line, text);
+                    self.emit(text, origin);
+                }
+            }
+
+            for mut diagnostic in diagnostics {
+                diagnostic.notes.extend(notes.clone());
+                self.diagnostics.push(diagnostic);
+            }
+
+            return;
+        }
+
+        let args: Vec<&str> = words.filter(|word| !word.is_empty()).collect();
+        let call = origin.locate(word_span(line, text, name));
+
+        if origin.calls.iter().any(|(outer, _)| outer == name) {
+            let mut diagnostic =
+                Diagnostic::error(format!("the `{name}` macro expands recursively"), call);
+            diagnostic.notes = origin.notes();
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+
+        let definition = &self.macros[name];
+
+        if args.len() != definition.params.len() {
+            let params = definition.params.len();
+            let mut diagnostic = Diagnostic::error(
+                format!(
+                    "the `{name}` macro takes {params} argument{} but {} {} given",
+                    if params == 1 { "" } else { "s" },
+                    args.len(),
+                    if args.len() == 1 { "was" } else { "were" },
+                ),
+                call,
+            );
+            diagnostic.notes = origin.notes();
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+
+        self.expansions += 1;
+
+        let mut substitutions: HashMap<&str, String> = definition
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(args.iter().map(|arg| arg.to_string()))
+            .collect();
+
+        // labels defined in the body are renamed so every expansion gets its own copy
+        for label in &definition.labels {
+            substitutions.insert(label, format!("{name}.{}${label}", self.expansions));
+        }
+
+        let file = definition.file;
+        let body: Vec<(usize, String, String)> = definition
+            .body
+            .iter()
+            .map(|(body_line, body_text)| {
+                (
+                    *body_line,
+                    body_text.clone(),
+                    substitute(body_text, &substitutions),
+                )
+            })
+            .collect();
+
+        let mut calls = vec![(name.to_string(), call)];
+        calls.extend(origin.calls);
+        let outer = self.enter();
+
+        for (body_line, original, expanded) in body {
+            let columns = if original == expanded {
+                None
+            } else {
+                Some(trimmed_columns(&original))
+            };
+
+            let origin = Origin {
+                file,
+                line: body_line,
+                columns,
+                calls: calls.clone(),
+                generated: false,
+            };
+
+            if self.conditional(body_line, &expanded, &origin) {
+                continue;
+            }
+
+            self.process(body_line, &expanded, origin);
+        }
+
+        self.leave(outer);
+    }
+
+    fn emit(&mut self, text: &str, origin: Origin) {
+        self.output.text.push_str(text);
+        self.output.text.push('\n');
+        self.output.origins.push(origin);
+    }
+}
+
+/// Splits a line into a directive (a word starting with `.`) and its arguments.
+fn directive(text: &str) -> Option<(&str, Vec<&str>)> {
+    let mut words = strip_comment(text)
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty());
+
+    match words.next() {
+        Some(word) if word.starts_with('.') => Some((word, words.collect())),
+        _ => None,
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    match comment_start(text) {
+        Some(i) => &text[..i],
+        None => text,
+    }
+}
+
+fn label_definition(text: &str) -> Option<&str> {
+    strip_comment(text)
+        .trim()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+fn is_identifier(word: &str) -> bool {
+    let mut chars = word.chars();
+
+    matches!(chars.next(), Some('A'..='Z' | 'a'..='z' | '_' | '$'))
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | '-')
+}
+
+// replaces whole words, leaving comments alone. labels can have `-` in them, so a word like
+// `skip-it` is replaced as a whole if it can be, and otherwise piece by piece, like `value-1`.
+fn substitute(text: &str, substitutions: &HashMap<&str, String>) -> String {
+    let code = strip_comment(text);
+    let mut out = String::with_capacity(text.len());
+    let mut word = String::new();
+
+    for c in code.chars() {
+        if is_word_char(c) {
+            word.push(c);
+            continue;
+        }
+
+        out.push_str(&substitute_word(&word, substitutions));
+        out.push(c);
+        word.clear();
+    }
+
+    out.push_str(&substitute_word(&word, substitutions));
+    out.push_str(&text[code.len()..]);
+    out
+}
+
+fn substitute_word(word: &str, substitutions: &HashMap<&str, String>) -> String {
+    match substitutions.get(word) {
+        Some(substitution) => substitution.clone(),
+        None => word
+            .split('-')
+            .map(|part| substitutions.get(part).map_or(part, String::as_str))
+            .collect::<Vec<_>>()
+            .join("-"),
+    }
+}
+
+// the span of the first occurrence of `word` on the line
+fn word_span(line: usize, text: &str, word: &str) -> Span {
+    let start = text.find(word).unwrap_or(0) + 1;
+    Span::new(line, start, start + word.len().max(1))
+}
+
+// the columns of the line without its leading and trailing whitespace or comment
+fn trimmed_columns(text: &str) -> (usize, usize) {
+    let code = strip_comment(text).trim_end();
+    let start = code.len() - code.trim_start().len() + 1;
+    (start, code.len().max(start) + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_macros_with_arguments_and_unique_labels() {
+        let source = ".macro COPY src dst\n@src\nD=M\n(SKIP)\n@dst\nM=D // copy\n.endm\nCOPY R0, R1\nCOPY R2 R3\n";
+        let (expansion, diagnostics) = Preprocessor::run(&mut Sources::from(source), &[]);
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            expansion.text,
+            "@R0\nD=M\n(COPY.1$SKIP)\n@R1\nM=D // copy\n@R2\nD=M\n(COPY.2$SKIP)\n@R3\nM=D // copy\n"
+        );
+        assert_eq!(expansion.origins[0].line, 2);
+        assert_eq!(expansion.origins[0].calls[0].1, Span::new(8, 1, 5));
+        assert_eq!(expansion.origins[1].columns, None);
+
+        // labels with `-` in them are renamed whole, and parameters next to a `-` still expand
+        let source = ".macro SKIP n\n@skip-it\n@n-1\n(skip-it)\n.endm\nSKIP 5\n";
+        let (expansion, _) = Preprocessor::run(&mut Sources::from(source), &[]);
+
+        assert_eq!(expansion.text, "@SKIP.1$skip-it\n@5-1\n(SKIP.1$skip-it)\n");
+    }
+
+    #[test]
+    fn reports_bad_invocations() {
+        let mut sources = Sources::from(".macro INC x\n@x\nM=M+1\n.endm\nINC\n.endm\n");
+        let (_, diagnostics) = Preprocessor::run(&mut sources, &[]);
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                "the `INC` macro takes 1 argument but 0 were given",
+                "`.endm` without a matching `.macro`"
+            ]
+        );
+    }
+
+    #[test]
+    fn assembles_conditionally() {
+        let source = "\
+.equ LEVEL 2
+.ifdef DEBUG
+.if LEVEL>1
+@1
+.if D>0
+@2
+.endif
+.else
+.include \"missing.asm\"
+.endif
+.else
+@4
+.endif
+.ifndef DEBUG
+@5
+.endif
+";
+        let defines = [("DEBUG".to_string(), 1)];
+        let (expansion, diagnostics) = Preprocessor::run(&mut Sources::from(source), &defines);
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            expansion.text,
+            ".equ LEVEL 2\n@1\n@if.1$else\nD;JLE\n@2\n(if.1$else)\n"
+        );
+
+        let (_, diagnostics) = Preprocessor::run(&mut Sources::from(".if MAX>1\n.ifndef X\n"), &[]);
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+
+        assert_eq!(
+            messages,
+            [
+                "`MAX` is not a label or constant",
+                "the `.ifndef` is never closed",
+                "the `.if` is never closed",
+            ]
+        );
+    }
+
+    #[test]
+    fn includes_files_relative_to_the_includer_and_detects_cycles() {
+        let dir = std::env::temp_dir().join(format!("hack-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("main.asm"), "@1\n.include \"lib/a.asm\"\n@4\n").unwrap();
+        std::fs::write(dir.join("lib/a.asm"), "@2\n.include \"b.asm\"\n").unwrap();
+        std::fs::write(dir.join("lib/b.asm"), "@3\n.include \"a.asm\"\n").unwrap();
+
+        let mut sources = Sources::new();
+        sources.load(&dir.join("main.asm")).unwrap();
+        let (expansion, diagnostics) = Preprocessor::run(&mut sources, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(expansion.text, "@1\n@2\n@3\n@4\n");
+        assert_eq!(expansion.origins[2].file, 2);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.ends_with("a.asm` includes itself"));
+    }
+}
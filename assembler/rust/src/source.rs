@@ -0,0 +1,108 @@
+use std::path::{Path, PathBuf};
+
+/// A file being assembled, either named on the command line or pulled in with `.include`.
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    /// The name diagnostics use for the file.
+    pub name: String,
+    /// Where the file was read from. In-memory sources don't have one.
+    pub path: Option<PathBuf>,
+    pub text: String,
+}
+
+impl SourceFile {
+    /// The directory `.include` paths in this file are relative to.
+    pub fn dir(&self) -> PathBuf {
+        match self.path.as_ref().and_then(|path| path.parent()) {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from("."),
+        }
+    }
+}
+
+/// Every file that went into a program. Spans refer to files by their index in this table.
+#[derive(Clone, Debug, Default)]
+pub struct Sources {
+    files: Vec<SourceFile>,
+    roots: Vec<usize>,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file held in memory to be assembled after the ones already added.
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> usize {
+        let id = self.insert(SourceFile {
+            name: name.into(),
+            path: None,
+            text: text.into(),
+        });
+
+        self.roots.push(id);
+        id
+    }
+
+    /// Adds a file held in memory as if it had been read from `path`, so its `.include` paths
+    /// are relative to it.
+    pub fn add_at(&mut self, path: &Path, text: impl Into<String>) -> usize {
+        let id = self.insert(SourceFile {
+            name: path.to_string_lossy().into_owned(),
+            path: Some(path.to_path_buf()),
+            text: text.into(),
+        });
+
+        self.roots.push(id);
+        id
+    }
+
+    /// Reads a file to be assembled after the ones already added.
+    pub fn load(&mut self, path: &Path) -> std::io::Result<usize> {
+        let id = self.read(path)?;
+        self.roots.push(id);
+        Ok(id)
+    }
+
+    /// Reads a file that's been included from one of the others.
+    pub(crate) fn read(&mut self, path: &Path) -> std::io::Result<usize> {
+        let text = std::fs::read_to_string(path)?;
+
+        Ok(self.insert(SourceFile {
+            name: path.to_string_lossy().into_owned(),
+            path: Some(path.to_path_buf()),
+            text,
+        }))
+    }
+
+    fn insert(&mut self, file: SourceFile) -> usize {
+        self.files.push(file);
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, id: usize) -> Option<&SourceFile> {
+        self.files.get(id)
+    }
+
+    /// The name diagnostics use for a file.
+    pub fn name(&self, id: usize) -> &str {
+        self.get(id).map_or("<input>", |file| file.name.as_str())
+    }
+
+    /// The files named on the command line (or added in memory), in the order they're assembled.
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &SourceFile)> {
+        self.files.iter().enumerate()
+    }
+}
+
+impl From<&str> for Sources {
+    fn from(text: &str) -> Self {
+        let mut sources = Self::new();
+        sources.add("<input>", text);
+        sources
+    }
+}
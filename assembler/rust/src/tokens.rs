@@ -0,0 +1,792 @@
+use crate::expr::Expr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Addr(AddrToken),
+    Comp(DestToken, CompToken, JumpToken),
+}
+impl Instruction {
+    /// The 16-bit machine word for this instruction.
+    pub fn encode(&self) -> u16 {
+        match self {
+            Instruction::Addr(addr) => match addr {
+                AddrToken::Static(addr) => (addr & 0x7fff) as u16,
+                AddrToken::Dynamic(_) | AddrToken::Expr(_) => {
+                    panic!("should not call encode on an unresolved addr")
+                }
+            },
+
+            Instruction::Comp(dest, comp, jump) => {
+                (comp.prefix() << 13 | comp.code() << 6 | dest.code() << 3 | jump.code()) as u16
+            }
+        }
+    }
+
+    /// The instruction in the course's textual .hack format, one line of 0s and 1s.
+    pub fn to_binary(&self) -> Vec<u8> {
+        format!("{:016b}\n", self.encode()).into_bytes()
+    }
+
+    /// Reverses encode. Returns None for C-instructions with the two unused bits cleared.
+    /// Undocumented comp bits decode to [`CompToken::Raw`].
+    pub fn decode(word: u16) -> Option<Self> {
+        Self::decode_for(word, Isa::Standard)
+    }
+
+    /// Like decode, but also decodes the instructions `isa` adds to the course's.
+    pub fn decode_for(word: u16, isa: Isa) -> Option<Self> {
+        let word = word as usize;
+
+        if word >> 15 == 0 {
+            return Some(Instruction::Addr(AddrToken::Static(word)));
+        }
+
+        let comp = word >> 6 & 0b1111111;
+        let comp = match word >> 13 {
+            0b111 => CompToken::from_bits(comp),
+            0b101 if isa == Isa::Extended => CompToken::from_shift_code(comp)?,
+            _ => return None,
+        };
+
+        Some(Instruction::Comp(
+            DestToken::from_code(word >> 3 & 0b111)?,
+            comp,
+            JumpToken::from_code(word & 0b111)?,
+        ))
+    }
+}
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Addr(AddrToken::Static(addr)) => write!(f, "@{addr}"),
+            Instruction::Addr(AddrToken::Dynamic(label)) => write!(f, "@{label}"),
+            Instruction::Addr(AddrToken::Expr(expr)) => write!(f, "@{expr}"),
+            Instruction::Comp(dest, comp, jump) => {
+                if *dest != DestToken::Empty {
+                    write!(f, "{dest}=")?;
+                }
+
+                write!(f, "{comp}")?;
+
+                if *jump != JumpToken::Empty {
+                    write!(f, ";{jump}")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The instructions the assembler accepts and the disassembler decodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Isa {
+    /// The instruction set from the course.
+    #[default]
+    Standard,
+    /// The course's instructions plus shifts (`D<<`, `A>>`, `M<<` and so on), which are
+    /// C-instructions that start with `101` instead of `111`.
+    Extended,
+}
+
+impl Isa {
+    /// The name `--isa` knows the instruction set by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Standard => "standard",
+            Self::Extended => "extended",
+        }
+    }
+}
+
+impl std::str::FromStr for Isa {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Standard, Self::Extended]
+            .into_iter()
+            .find(|isa| isa.name() == s)
+            .ok_or_else(|| {
+                format!("unknown instruction set `{s}`, expected `standard` or `extended`")
+            })
+    }
+}
+
+/// What the [`Lexer`](crate::lexer::Lexer) splits source into. Names are borrowed from the
+/// source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token<'a> {
+    Dest(DestToken),
+    Comp(CompToken),
+    Jump(JumpToken),
+    Addr(Operand<'a>),
+    Label(&'a str),
+    NumericLabel(usize),
+    Equ(&'a str, Expr),
+    /// `.org`, `.fill` or `.align`, with its operand.
+    Layout(Layout, Expr),
+    /// `.data NAME`, which starts a table in RAM.
+    Data(&'a str),
+    /// The values from a `.word` or `.string`, which add to the last table.
+    Words(Vec<Expr>),
+    /// Something the lexer couldn't make sense of, which it has recorded a diagnostic for.
+    Invalid,
+}
+
+/// Shows a token the way it's written in the source, for diagnostics.
+impl std::fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dest(dest) => write!(f, "{dest}="),
+            Self::Comp(comp) => write!(f, "{comp}"),
+            Self::Jump(jump) => write!(f, ";{jump}"),
+            Self::Addr(Operand::Number(addr)) => write!(f, "@{addr}"),
+            Self::Addr(Operand::Symbol(name)) => write!(f, "@{name}"),
+            Self::Addr(Operand::Expr(expr)) => write!(f, "@{expr}"),
+            Self::Label(name) => write!(f, "({name})"),
+            Self::NumericLabel(n) => write!(f, "{n}:"),
+            Self::Equ(name, expr) => write!(f, ".equ {name} {expr}"),
+            Self::Layout(layout, expr) => write!(f, "{layout} {expr}"),
+            Self::Data(name) => write!(f, ".data {name}"),
+            Self::Words(words) => {
+                let words: Vec<_> = words.iter().map(Expr::to_string).collect();
+                write!(f, ".word {}", words.join(", "))
+            }
+            Self::Invalid => Ok(()),
+        }
+    }
+}
+
+/// A directive that pads ROM to place the code after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// `.org ADDR` pads up to an address.
+    Org,
+    /// `.fill N` pads with N words.
+    Fill,
+    /// `.align N` pads up to the next multiple of N.
+    Align,
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Org => ".org",
+            Self::Fill => ".fill",
+            Self::Align => ".align",
+        })
+    }
+}
+
+/// The operand of an A-instruction as it was written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operand<'a> {
+    Number(usize),
+    Symbol(&'a str),
+    Expr(Expr),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DestToken {
+    A,
+    AD,
+    ADM,
+    AM,
+    D,
+    Empty,
+    M,
+    MD,
+}
+impl DestToken {
+    pub fn code(&self) -> usize {
+        match self {
+            Self::Empty => 0,
+            Self::M => 1,
+            Self::D => 2,
+            Self::MD => 3,
+            Self::A => 4,
+            Self::AM => 5,
+            Self::AD => 6,
+            Self::ADM => 7,
+        }
+    }
+
+    pub const ALL: [Self; 8] = [
+        Self::Empty,
+        Self::M,
+        Self::D,
+        Self::MD,
+        Self::A,
+        Self::AM,
+        Self::AD,
+        Self::ADM,
+    ];
+
+    pub fn from_code(code: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|dest| dest.code() == code)
+    }
+
+    /// Parses the registers of a dest field, without the `=`. They can be in any order, but
+    /// each can only be named once.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut code = 0;
+
+        for c in s.chars() {
+            let bit = match c {
+                'A' => 0b100,
+                'D' => 0b010,
+                'M' => 0b001,
+                _ => return None,
+            };
+
+            if code & bit != 0 {
+                return None;
+            }
+
+            code |= bit;
+        }
+
+        match code {
+            0 => None,
+            _ => Self::from_code(code),
+        }
+    }
+}
+impl std::fmt::Display for DestToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => Ok(()),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompToken {
+    Zero,
+    One,
+    A,
+    D,
+    M,
+    AddA1,
+    AddD1,
+    AddDA,
+    AddDM,
+    AddM1,
+    AndDA,
+    AndDM,
+    InvertA,
+    InvertD,
+    InvertM,
+    Negate1,
+    NegateA,
+    NegateD,
+    NegateM,
+    OrDA,
+    OrDM,
+    SubA1,
+    SubAD,
+    SubD1,
+    SubDA,
+    SubDM,
+    SubM1,
+    SubMD,
+    ShiftLeftA,
+    ShiftLeftD,
+    ShiftLeftM,
+    ShiftRightA,
+    ShiftRightD,
+    ShiftRightM,
+    /// Comp bits that don't have a name, written `%comp(0b0000001)` or
+    /// `%ALU(zx=0,nx=0,zy=0,ny=0,f=0,no=1)`. Never holds the bits of a documented computation.
+    Raw(usize),
+}
+impl CompToken {
+    pub fn code(&self) -> usize {
+        match self {
+            Self::Zero => 0b0101010,
+            Self::One => 0b0111111,
+            Self::A => 0b0110000,
+            Self::D => 0b0001100,
+            Self::M => 0b1110000,
+            Self::AddA1 => 0b0110111,
+            Self::AddD1 => 0b0011111,
+            Self::AddDA => 0b0000010,
+            Self::AddDM => 0b1000010,
+            Self::AddM1 => 0b1110111,
+            Self::AndDA => 0b0000000,
+            Self::AndDM => 0b1000000,
+            Self::InvertA => 0b0110001,
+            Self::InvertD => 0b0001101,
+            Self::InvertM => 0b1110001,
+            Self::Negate1 => 0b0111010,
+            Self::NegateA => 0b0110011,
+            Self::NegateD => 0b0001111,
+            Self::NegateM => 0b1110011,
+            Self::OrDA => 0b0010101,
+            Self::OrDM => 0b1010101,
+            Self::SubA1 => 0b0110010,
+            Self::SubAD => 0b0000111,
+            Self::SubD1 => 0b0001110,
+            Self::SubDA => 0b0010011,
+            Self::SubDM => 0b1010011,
+            Self::SubM1 => 0b1110010,
+            Self::SubMD => 0b1000111,
+            Self::ShiftLeftA => 0b0100000,
+            Self::ShiftLeftD => 0b0110000,
+            Self::ShiftLeftM => 0b1100000,
+            Self::ShiftRightA => 0b0000000,
+            Self::ShiftRightD => 0b0010000,
+            Self::ShiftRightM => 0b1000000,
+            Self::Raw(code) => *code,
+        }
+    }
+
+    /// The bits a C-instruction starts with: `111`, or `101` for the extended set's shifts,
+    /// whose comp bits overlap the others'.
+    pub fn prefix(&self) -> usize {
+        if self.is_shift() {
+            0b101
+        } else {
+            0b111
+        }
+    }
+
+    /// Whether this is one of the shifts from the extended instruction set.
+    pub fn is_shift(&self) -> bool {
+        Self::SHIFTS.contains(self)
+    }
+
+    pub const SHIFTS: [Self; 6] = [
+        Self::ShiftLeftA,
+        Self::ShiftLeftD,
+        Self::ShiftLeftM,
+        Self::ShiftRightA,
+        Self::ShiftRightD,
+        Self::ShiftRightM,
+    ];
+
+    /// The computations in the course's instruction set, without the extended set's shifts.
+    pub const ALL: [Self; 28] = [
+        Self::Zero,
+        Self::One,
+        Self::A,
+        Self::D,
+        Self::M,
+        Self::AddA1,
+        Self::AddD1,
+        Self::AddDA,
+        Self::AddDM,
+        Self::AddM1,
+        Self::AndDA,
+        Self::AndDM,
+        Self::InvertA,
+        Self::InvertD,
+        Self::InvertM,
+        Self::Negate1,
+        Self::NegateA,
+        Self::NegateD,
+        Self::NegateM,
+        Self::OrDA,
+        Self::OrDM,
+        Self::SubA1,
+        Self::SubAD,
+        Self::SubD1,
+        Self::SubDA,
+        Self::SubDM,
+        Self::SubM1,
+        Self::SubMD,
+    ];
+
+    /// Returns None for the comp bits that don't correspond to a documented computation.
+    pub fn from_code(code: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|comp| comp.code() == code)
+    }
+
+    /// Like from_code, but comp bits that don't have a name are [`CompToken::Raw`].
+    pub fn from_bits(code: usize) -> Self {
+        Self::from_code(code).unwrap_or(Self::Raw(code))
+    }
+
+    /// Like from_code, for the comp bits of a C-instruction that starts with `101`.
+    pub fn from_shift_code(code: usize) -> Option<Self> {
+        Self::SHIFTS.into_iter().find(|comp| comp.code() == code)
+    }
+
+    /// Parses a comp field. The operands of `+`, `&` and `|` can be written either way around,
+    /// so `M+D`, `1+D` and `A&D` are the same as `D+M`, `D+1` and `D&A`.
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(escape) = s.strip_prefix('%') {
+            return Self::escape(escape);
+        }
+
+        match *s.as_bytes() {
+            [b'0'] => Some(Self::Zero),
+            [b'1'] => Some(Self::One),
+            [b'-', b'1'] => Some(Self::Negate1),
+            [x] => Self::unary(' ', x as char),
+            [op @ (b'!' | b'-'), x] => Self::unary(op as char, x as char),
+            [x, op @ (b'+' | b'&' | b'|'), y] => {
+                let (x, op, y) = (x as char, op as char, y as char);
+                // D comes first, and 1 last, in the canonical spelling
+                let order = |c| match c {
+                    'D' => 0,
+                    '1' => 2,
+                    _ => 1,
+                };
+
+                if order(x) <= order(y) {
+                    Self::binary(x, op, y)
+                } else {
+                    Self::binary(y, op, x)
+                }
+            }
+            [x, b'-', y] => Self::binary(x as char, '-', y as char),
+            [x, b'<', b'<'] => Self::shift(x as char, '<'),
+            [x, b'>', b'>'] => Self::shift(x as char, '>'),
+            _ => None,
+        }
+    }
+
+    fn unary(op: char, x: char) -> Option<Self> {
+        let comp = match (op, x) {
+            (' ', 'A') => Self::A,
+            (' ', 'D') => Self::D,
+            (' ', 'M') => Self::M,
+            ('!', 'A') => Self::InvertA,
+            ('!', 'D') => Self::InvertD,
+            ('!', 'M') => Self::InvertM,
+            ('-', 'A') => Self::NegateA,
+            ('-', 'D') => Self::NegateD,
+            ('-', 'M') => Self::NegateM,
+            _ => return None,
+        };
+
+        Some(comp)
+    }
+
+    // `comp(bits)` or `ALU(zx=..,nx=..,zy=..,ny=..,f=..,no=..)`, where `a=1` optionally
+    // selects M instead of A
+    fn escape(s: &str) -> Option<Self> {
+        const BITS: [&str; 7] = ["a", "zx", "nx", "zy", "ny", "f", "no"];
+
+        let (kind, args) = s.strip_suffix(')')?.split_once('(')?;
+
+        let code = match kind {
+            "comp" => {
+                let value = Expr::parse(args.trim())
+                    .ok()?
+                    .eval(&mut |_| Err(String::new()))
+                    .ok()?;
+
+                usize::try_from(value).ok().filter(|code| *code < 1 << 7)?
+            }
+            "ALU" => {
+                let mut code = 0;
+                let mut given = [false; 7];
+
+                for arg in args.split(',') {
+                    let (name, value) = arg.split_once('=')?;
+                    let bit = BITS.iter().position(|bit| *bit == name.trim())?;
+
+                    if std::mem::replace(&mut given[bit], true) {
+                        return None;
+                    }
+
+                    match value.trim() {
+                        "0" => (),
+                        "1" => code |= 1 << (6 - bit),
+                        _ => return None,
+                    }
+                }
+
+                // every control bit has to be spelled out, but the a-bit defaults to A
+                if !given[1..].iter().all(|given| *given) {
+                    return None;
+                }
+
+                code
+            }
+            _ => return None,
+        };
+
+        Some(Self::from_bits(code))
+    }
+
+    fn shift(x: char, direction: char) -> Option<Self> {
+        let comp = match (x, direction) {
+            ('A', '<') => Self::ShiftLeftA,
+            ('D', '<') => Self::ShiftLeftD,
+            ('M', '<') => Self::ShiftLeftM,
+            ('A', '>') => Self::ShiftRightA,
+            ('D', '>') => Self::ShiftRightD,
+            ('M', '>') => Self::ShiftRightM,
+            _ => return None,
+        };
+
+        Some(comp)
+    }
+
+    fn binary(x: char, op: char, y: char) -> Option<Self> {
+        let comp = match (x, op, y) {
+            ('A', '+', '1') => Self::AddA1,
+            ('D', '+', '1') => Self::AddD1,
+            ('M', '+', '1') => Self::AddM1,
+            ('D', '+', 'A') => Self::AddDA,
+            ('D', '+', 'M') => Self::AddDM,
+            ('A', '-', '1') => Self::SubA1,
+            ('D', '-', '1') => Self::SubD1,
+            ('M', '-', '1') => Self::SubM1,
+            ('D', '-', 'A') => Self::SubDA,
+            ('D', '-', 'M') => Self::SubDM,
+            ('A', '-', 'D') => Self::SubAD,
+            ('M', '-', 'D') => Self::SubMD,
+            ('D', '&', 'A') => Self::AndDA,
+            ('D', '&', 'M') => Self::AndDM,
+            ('D', '|', 'A') => Self::OrDA,
+            ('D', '|', 'M') => Self::OrDM,
+            _ => return None,
+        };
+
+        Some(comp)
+    }
+}
+impl std::fmt::Display for CompToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Zero => "0",
+            Self::One => "1",
+            Self::A => "A",
+            Self::D => "D",
+            Self::M => "M",
+            Self::AddA1 => "A+1",
+            Self::AddD1 => "D+1",
+            Self::AddDA => "D+A",
+            Self::AddDM => "D+M",
+            Self::AddM1 => "M+1",
+            Self::AndDA => "D&A",
+            Self::AndDM => "D&M",
+            Self::InvertA => "!A",
+            Self::InvertD => "!D",
+            Self::InvertM => "!M",
+            Self::Negate1 => "-1",
+            Self::NegateA => "-A",
+            Self::NegateD => "-D",
+            Self::NegateM => "-M",
+            Self::OrDA => "D|A",
+            Self::OrDM => "D|M",
+            Self::SubA1 => "A-1",
+            Self::SubAD => "A-D",
+            Self::SubD1 => "D-1",
+            Self::SubDA => "D-A",
+            Self::SubDM => "D-M",
+            Self::SubM1 => "M-1",
+            Self::SubMD => "M-D",
+            Self::ShiftLeftA => "A<<",
+            Self::ShiftLeftD => "D<<",
+            Self::ShiftLeftM => "M<<",
+            Self::ShiftRightA => "A>>",
+            Self::ShiftRightD => "D>>",
+            Self::ShiftRightM => "M>>",
+            Self::Raw(code) => return write!(f, "%comp(0b{code:07b})"),
+        };
+
+        write!(f, "{s}")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JumpToken {
+    JEQ,
+    JGE,
+    JGT,
+    JLE,
+    JLT,
+    JMP,
+    JNE,
+    Empty,
+}
+impl JumpToken {
+    pub fn code(&self) -> usize {
+        match self {
+            Self::Empty => 0,
+            Self::JGT => 1,
+            Self::JEQ => 2,
+            Self::JGE => 3,
+            Self::JLT => 4,
+            Self::JNE => 5,
+            Self::JLE => 6,
+            Self::JMP => 7,
+        }
+    }
+
+    pub const ALL: [Self; 8] = [
+        Self::Empty,
+        Self::JGT,
+        Self::JEQ,
+        Self::JGE,
+        Self::JLT,
+        Self::JNE,
+        Self::JLE,
+        Self::JMP,
+    ];
+
+    pub fn from_code(code: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|jump| jump.code() == code)
+    }
+
+    /// The jump that's taken exactly when this one isn't.
+    pub fn negate(&self) -> Self {
+        Self::from_code(self.code() ^ 0b111).unwrap()
+    }
+}
+impl std::fmt::Display for JumpToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => Ok(()),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddrToken {
+    Static(usize),
+    Dynamic(String),
+    /// A constant expression, evaluated once every label is known.
+    Expr(Expr),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_spelling_of_comp() {
+        let spellings: [(CompToken, &[&str]); 28] = [
+            (CompToken::Zero, &["0"]),
+            (CompToken::One, &["1"]),
+            (CompToken::Negate1, &["-1"]),
+            (CompToken::A, &["A"]),
+            (CompToken::D, &["D"]),
+            (CompToken::M, &["M"]),
+            (CompToken::InvertA, &["!A"]),
+            (CompToken::InvertD, &["!D"]),
+            (CompToken::InvertM, &["!M"]),
+            (CompToken::NegateA, &["-A"]),
+            (CompToken::NegateD, &["-D"]),
+            (CompToken::NegateM, &["-M"]),
+            (CompToken::AddA1, &["A+1", "1+A"]),
+            (CompToken::AddD1, &["D+1", "1+D"]),
+            (CompToken::AddM1, &["M+1", "1+M"]),
+            (CompToken::AddDA, &["D+A", "A+D"]),
+            (CompToken::AddDM, &["D+M", "M+D"]),
+            (CompToken::SubA1, &["A-1"]),
+            (CompToken::SubD1, &["D-1"]),
+            (CompToken::SubM1, &["M-1"]),
+            (CompToken::SubDA, &["D-A"]),
+            (CompToken::SubDM, &["D-M"]),
+            (CompToken::SubAD, &["A-D"]),
+            (CompToken::SubMD, &["M-D"]),
+            (CompToken::AndDA, &["D&A", "A&D"]),
+            (CompToken::AndDM, &["D&M", "M&D"]),
+            (CompToken::OrDA, &["D|A", "A|D"]),
+            (CompToken::OrDM, &["D|M", "M|D"]),
+        ];
+
+        for (comp, spellings) in spellings {
+            assert_eq!(CompToken::parse(&comp.to_string()), Some(comp.clone()));
+
+            for spelling in spellings {
+                assert_eq!(CompToken::parse(spelling), Some(comp.clone()), "{spelling}");
+            }
+        }
+
+        for invalid in [
+            "", "A+M", "1-D", "D+D", "1+1", "!1", "D&1", "D+", "+D", "D+A+1", "X",
+        ] {
+            assert_eq!(CompToken::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn encodes_shifts_with_their_own_prefix() {
+        let shift = Instruction::Comp(
+            DestToken::M,
+            CompToken::parse("D<<").unwrap(),
+            JumpToken::Empty,
+        );
+
+        assert_eq!(shift.encode(), 0b1010110000001000);
+        assert_eq!(
+            Instruction::decode_for(shift.encode(), Isa::Extended),
+            Some(shift.clone())
+        );
+        assert_eq!(Instruction::decode(shift.encode()), None);
+        assert_eq!(shift.to_string(), "M=D<<");
+        assert_eq!(CompToken::parse("1<<"), None);
+    }
+
+    #[test]
+    fn escapes_comp_bits_without_a_name() {
+        let raw = CompToken::parse("%ALU(zx=0, nx=0, zy=0, ny=0, f=0, no=1)").unwrap();
+
+        assert_eq!(raw, CompToken::Raw(0b0000001));
+        assert_eq!(raw.to_string(), "%comp(0b0000001)");
+        assert_eq!(CompToken::parse("%comp(0b0000001)"), Some(raw));
+        assert_eq!(
+            CompToken::parse("%ALU(a=1,zx=1,nx=1,zy=0,ny=1,f=1,no=0)"),
+            Some(CompToken::Raw(0b1110110))
+        );
+        assert_eq!(CompToken::parse("%comp(0x2a)"), Some(CompToken::Zero));
+        assert_eq!(
+            Instruction::decode(0b1110000001010000).unwrap().to_string(),
+            "D=%comp(0b0000001)"
+        );
+
+        for invalid in [
+            "%comp(128)",
+            "%ALU(zx=1)",
+            "%ALU(zx=1,zx=0,nx=0,zy=0,ny=0,f=0,no=0)",
+            "%ALU(zx=2,nx=0,zy=0,ny=0,f=0,no=0)",
+            "%foo(1)",
+        ] {
+            assert_eq!(CompToken::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_dest_registers_in_any_order() {
+        let spellings: [(DestToken, &[&str]); 7] = [
+            (DestToken::M, &["M"]),
+            (DestToken::D, &["D"]),
+            (DestToken::A, &["A"]),
+            (DestToken::MD, &["MD", "DM"]),
+            (DestToken::AM, &["AM", "MA"]),
+            (DestToken::AD, &["AD", "DA"]),
+            (DestToken::ADM, &["ADM", "AMD", "DAM", "DMA", "MAD", "MDA"]),
+        ];
+
+        for (dest, spellings) in spellings {
+            for spelling in spellings {
+                assert_eq!(DestToken::parse(spelling), Some(dest.clone()), "{spelling}");
+            }
+        }
+
+        for invalid in ["", "AA", "MDM", "X"] {
+            assert_eq!(DestToken::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn shows_tokens_as_written() {
+        assert_eq!(Token::Dest(DestToken::MD).to_string(), "MD=");
+        assert_eq!(Token::Jump(JumpToken::JGT).to_string(), ";JGT");
+        assert_eq!(Token::Addr(Operand::Symbol("LOOP")).to_string(), "@LOOP");
+        assert_eq!(Token::Label("LOOP").to_string(), "(LOOP)");
+        assert_eq!(
+            Token::Words(vec![Expr::Number(5), Expr::Symbol("x".to_string())]).to_string(),
+            ".word 5, x"
+        );
+    }
+}
@@ -36,7 +36,7 @@ test-project-six() {
 	while read -rs asm; do
 		(
 			cd "${root_dir}/assembler/rust"
-			cargo run "${asm}" >/dev/null 2>&1
+			cargo run -- build "${asm}" >/dev/null 2>&1
 		)
 
 		if diff_output=$(diff -u --color=always "${asm%.asm}.cmp" "${asm%.asm}.hack"); then